@@ -1,17 +1,56 @@
-use common::commands::{Command, Target};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use common::commands::{
+    Capability, ChatMessage, Command, LeaveReason, MessageId, Role, Status, Target,
+};
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{inputs::key::Key, io::IoEvent};
 
 use self::{
     actions::{Action, Actions},
-    state::{Active, Pane, State},
+    state::{Active, Pane, RoomUser, State},
 };
 
 pub mod actions;
 pub mod state;
 
-pub type Message = (String, String);
+/// How often others are told we're typing while we keep at it
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A line in a room or chat
+#[derive(Debug, Clone)]
+pub enum Message {
+    Chat(ChatMessage),
+    /// Someone joined the room, stamped when it arrived
+    Joined {
+        timestamp: DateTime<Utc>,
+        username: String,
+    },
+    Left {
+        timestamp: DateTime<Utc>,
+        username: String,
+        reason: LeaveReason,
+    },
+}
+
+impl Message {
+    /// Id the server gave the message, if it's a chat message
+    pub fn id(&self) -> Option<MessageId> {
+        match self {
+            Message::Chat(message) => Some(message.id),
+            _ => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Message::Chat(message) => message.timestamp,
+            Message::Joined { timestamp, .. } | Message::Left { timestamp, .. } => *timestamp,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum AppReturn {
@@ -22,14 +61,26 @@ pub enum AppReturn {
 pub struct App {
     actions: Actions,
     io_tx: UnboundedSender<IoEvent>,
+    /// How long without a key press before the server's told we're idle,
+    /// never if none
+    away_after: Option<Duration>,
+    last_input: Instant,
+    /// Whether the server was told we're idle, undone by the next key press
+    idle: bool,
+    /// Where and when we last said we're typing
+    typing_sent: Option<(Target, Instant)>,
     pub state: State,
 }
 
 impl App {
-    pub fn new(io_tx: UnboundedSender<IoEvent>) -> Self {
+    pub fn new(io_tx: UnboundedSender<IoEvent>, away_after: Option<Duration>) -> Self {
         let mut app = Self {
             io_tx,
             actions: Actions::from(vec![Action::Quit]),
+            away_after,
+            last_input: Instant::now(),
+            idle: false,
+            typing_sent: None,
             state: State::default(),
         };
 
@@ -44,9 +95,56 @@ impl App {
     }
 
     pub fn update_on_tick(&mut self) -> AppReturn {
+        let idle_for = self.last_input.elapsed();
+        if !self.idle && self.away_after.is_some_and(|after| idle_for >= after) {
+            self.idle = true;
+            self.set_idle(true);
+        }
+        self.state.expire_typing();
+
+        // Whatever's on screen counts as read
+        if let Some(event) = self.state.mark_current_read() {
+            self.dispatch(event);
+        }
         AppReturn::Continue
     }
 
+    /// Let the server know whether we've left this session alone, it shows
+    /// us as away once every session has been and we hadn't set a status
+    fn set_idle(&mut self, idle: bool) {
+        if !self.state.supports(Capability::Statuses) {
+            return;
+        }
+        self.dispatch(IoEvent::Command(Command::Idle { idle }));
+    }
+
+    /// A new session starts off not idle, so it's told again on the next tick
+    /// if we still are
+    pub fn session_started(&mut self) {
+        self.idle = false;
+    }
+
+    /// Tell the selected room or chat we're typing, unless we did recently
+    fn typing(&mut self) {
+        if !self.state.supports(Capability::Typing) {
+            return;
+        }
+        let Some(target) = self.state.current_target() else {
+            return;
+        };
+        let recent = self
+            .typing_sent
+            .as_ref()
+            .is_some_and(|(sent_to, at)| *sent_to == target && at.elapsed() < TYPING_INTERVAL);
+
+        if !recent {
+            self.dispatch(IoEvent::Command(Command::Typing {
+                target: target.clone(),
+            }));
+            self.typing_sent = Some((target, Instant::now()));
+        }
+    }
+
     pub fn current_actions(&self) -> &Actions {
         &self.actions
     }
@@ -58,6 +156,10 @@ impl App {
             state::Pane::Rooms => vec![
                 Action::NewRoom,
                 Action::LeaveRoom,
+                Action::Topic,
+                Action::Private,
+                Action::Invitations,
+                Action::Status,
                 Action::RoomUsers,
                 Action::Messages,
                 Action::MaybeFocusNewMessage,
@@ -70,6 +172,7 @@ impl App {
                 Action::Sleep,
             ],
             state::Pane::Chats => vec![
+                Action::Status,
                 Action::Messages,
                 Action::MaybeFocusNewMessage,
                 Action::AllUsers,
@@ -91,6 +194,9 @@ impl App {
             state::Pane::NewMessage => vec![Action::SendMessage, Action::Escape],
             state::Pane::Users => vec![
                 Action::NewChat,
+                Action::Kick,
+                Action::Ban,
+                Action::Operator,
                 Action::ListPrev,
                 Action::ListNext,
                 Action::AllUsers,
@@ -100,8 +206,19 @@ impl App {
                 Action::Sleep,
             ],
             state::Pane::NewRoom => vec![Action::JoinOrCreateRoom, Action::Escape],
+            state::Pane::Topic => vec![Action::SetTopic, Action::Escape],
+            state::Pane::RoomKey => vec![Action::SetPrivate, Action::Escape],
+            state::Pane::Status => vec![Action::SetStatus, Action::Escape],
+            state::Pane::Invitations => vec![
+                Action::AcceptInvite,
+                Action::ListPrev,
+                Action::ListNext,
+                Action::Escape,
+                Action::Quit,
+            ],
             state::Pane::AllUsers => vec![
                 Action::NewChat,
+                Action::Invite,
                 Action::ListPrev,
                 Action::ListNext,
                 Action::AllUsers,
@@ -126,6 +243,15 @@ impl App {
     }
 
     pub fn do_action(&mut self, key: Key, username: &str) -> AppReturn {
+        // Errors stay up until the next key press
+        self.state.error = None;
+
+        self.last_input = Instant::now();
+        if self.idle {
+            self.idle = false;
+            self.set_idle(false);
+        }
+
         if let Some(action) = self.actions.find(key) {
             match action {
                 Action::Quit => AppReturn::Exit,
@@ -143,6 +269,37 @@ impl App {
                     }
                     AppReturn::Continue
                 }
+                Action::Topic => {
+                    if let Some(Active::Room) = self.state.active_list() {
+                        self.state.new_topic =
+                            self.state.current_topic().unwrap_or_default().into();
+                        self.focus_pane(Pane::Topic);
+                    }
+                    AppReturn::Continue
+                }
+                Action::Private => {
+                    let private = self.state.current_room_info().map(|info| info.private);
+                    match (self.state.active_rooms.selected_item().cloned(), private) {
+                        (Some(room), Some(true)) => {
+                            self.dispatch(IoEvent::Command(Command::SetPrivate {
+                                room,
+                                private: false,
+                                key: None,
+                            }))
+                        }
+                        (Some(_), _) => self.focus_pane(Pane::RoomKey),
+                        (None, _) => {}
+                    }
+                    AppReturn::Continue
+                }
+                Action::Invitations => {
+                    self.focus_pane(Pane::Invitations);
+                    AppReturn::Continue
+                }
+                Action::Status => {
+                    self.focus_pane(Pane::Status);
+                    AppReturn::Continue
+                }
                 Action::RoomUsers => {
                     self.focus_pane(Pane::Users);
                     AppReturn::Continue
@@ -173,6 +330,20 @@ impl App {
                     AppReturn::Continue
                 }
                 Action::ListPrev => {
+                    // Scrolling up from the oldest message loads older history, as
+                    // does scrolling when there's nothing on screen to scroll through
+                    let at_top = self
+                        .state
+                        .current_messages_mut()
+                        .map(|l| l.items.is_empty() || l.selected().unwrap_or(0) == 0)
+                        .unwrap_or_default();
+                    if self.state.current_pane() == Pane::Messages && at_top {
+                        if let Some(event) = self.state.fetch_history() {
+                            self.dispatch(event);
+                        }
+                        return AppReturn::Continue;
+                    }
+
                     match self.state.current_pane() {
                         Pane::Rooms => self.state.active_rooms.previous(),
                         Pane::Chats => self.state.active_chats.previous(),
@@ -188,7 +359,12 @@ impl App {
                             .unwrap_or_default(),
                         Pane::AllUsers => self.state.all_users.previous(),
                         Pane::AllRooms => self.state.all_rooms.previous(),
-                        Pane::NewRoom | Pane::NewMessage => unreachable!(),
+                        Pane::Invitations => self.state.invitations.previous(),
+                        Pane::NewRoom
+                        | Pane::NewMessage
+                        | Pane::Topic
+                        | Pane::RoomKey
+                        | Pane::Status => unreachable!(),
                     };
                     AppReturn::Continue
                 }
@@ -208,7 +384,12 @@ impl App {
                             .unwrap_or_default(),
                         Pane::AllUsers => self.state.all_users.next(),
                         Pane::AllRooms => self.state.all_rooms.next(),
-                        Pane::NewRoom | Pane::NewMessage => unreachable!(),
+                        Pane::Invitations => self.state.invitations.next(),
+                        Pane::NewRoom
+                        | Pane::NewMessage
+                        | Pane::Topic
+                        | Pane::RoomKey
+                        | Pane::Status => unreachable!(),
                     };
                     AppReturn::Continue
                 }
@@ -217,8 +398,13 @@ impl App {
                         Pane::Users => self
                             .state
                             .current_room_users_mut()
-                            .and_then(|r| r.selected_item().cloned()),
-                        Pane::AllUsers => self.state.all_users.selected_item().cloned(),
+                            .and_then(|r| r.selected_item())
+                            .map(|u| u.username().to_owned()),
+                        Pane::AllUsers => self
+                            .state
+                            .all_users
+                            .selected_item()
+                            .map(|u| u.username.clone()),
                         _ => unreachable!(),
                     };
 
@@ -230,32 +416,131 @@ impl App {
                     }
                     AppReturn::Continue
                 }
+                Action::Kick => {
+                    if let Some((room, user)) = self.state.selected_room_user() {
+                        let user = user.username().to_owned();
+                        self.dispatch(IoEvent::Command(Command::Kick { room, user }));
+                    }
+                    AppReturn::Continue
+                }
+                Action::Ban => {
+                    let command = match self.state.selected_room_user() {
+                        Some((room, RoomUser::Banned(user))) => Some(Command::Unban { room, user }),
+                        Some((room, RoomUser::Member(member))) => Some(Command::Ban {
+                            room,
+                            user: member.username,
+                        }),
+                        None => None,
+                    };
+                    if let Some(command) = command {
+                        self.dispatch(IoEvent::Command(command));
+                    }
+                    AppReturn::Continue
+                }
+                Action::Operator => {
+                    if let Some((room, RoomUser::Member(member))) = self.state.selected_room_user()
+                    {
+                        self.dispatch(IoEvent::Command(Command::SetOperator {
+                            room,
+                            user: member.username,
+                            operator: member.role == Role::Member,
+                        }));
+                    }
+                    AppReturn::Continue
+                }
+                Action::Invite => {
+                    let room = self.state.active_rooms.selected_item().cloned();
+                    let user = self
+                        .state
+                        .all_users
+                        .selected_item()
+                        .map(|u| u.username.clone());
+                    if let (Some(room), Some(user)) = (room, user) {
+                        self.dispatch(IoEvent::Command(Command::Invite { room, user }));
+                    }
+                    AppReturn::Continue
+                }
                 Action::JoinRoom => {
-                    if let Some(room) = self.state.all_rooms.selected_item().cloned() {
-                        self.dispatch(IoEvent::Command(Command::JoinOrCreate { room }));
+                    if let Some(room) = self.state.all_rooms.selected_item() {
+                        let room = room.name.clone();
+                        self.dispatch(IoEvent::Command(Command::JoinOrCreate { room, key: None }));
                         self.focus_pane(Pane::Rooms)
                     }
                     AppReturn::Continue
                 }
                 Action::JoinOrCreateRoom => {
-                    self.dispatch(IoEvent::Command(Command::JoinOrCreate {
-                        room: self.state.new_room.to_owned(),
-                    }));
+                    // Like irc a key for private rooms can follow the name
+                    let mut input = self.state.new_room.split_whitespace();
+                    if let Some(room) = input.next() {
+                        let key = input.next().map(str::to_owned);
+                        let event = self.state.join_room(room.to_owned(), key);
+                        self.dispatch(event);
+                    }
                     self.state.new_room.clear();
                     self.focus_pane(Pane::Rooms);
                     AppReturn::Continue
                 }
+                Action::SetTopic => {
+                    if let Some(room) = self.state.active_rooms.selected_item().cloned() {
+                        self.dispatch(IoEvent::Command(Command::SetTopic {
+                            room,
+                            topic: self.state.new_topic.to_owned(),
+                        }));
+                    }
+                    self.state.new_topic.clear();
+                    self.focus_pane(Pane::Rooms);
+                    AppReturn::Continue
+                }
+                Action::SetPrivate => {
+                    if let Some(room) = self.state.active_rooms.selected_item().cloned() {
+                        self.dispatch(IoEvent::Command(Command::SetPrivate {
+                            room,
+                            private: true,
+                            key: Some(self.state.new_key.to_owned()),
+                        }));
+                    }
+                    self.state.new_key.clear();
+                    self.focus_pane(Pane::Rooms);
+                    AppReturn::Continue
+                }
+                Action::SetStatus => {
+                    // The status comes first and anything after it is the note
+                    let input = self.state.new_status.trim();
+                    let (status, note) = input.split_once(' ').unwrap_or((input, ""));
+                    let status = match status.to_lowercase().as_str() {
+                        "online" => Some(Status::Online),
+                        "away" => Some(Status::Away),
+                        "busy" => Some(Status::Busy),
+                        _ => None,
+                    };
+                    let note = Some(note.trim().to_owned()).filter(|n| !n.is_empty());
+
+                    if !self.state.supports(Capability::Statuses) {
+                        self.state.error = Some("the server doesn't support statuses".into());
+                    } else if let Some(status) = status {
+                        self.dispatch(IoEvent::Command(Command::SetStatus { status, note }));
+                    } else {
+                        self.state.error = Some("status has to be online, away or busy".into());
+                    }
+                    self.state.new_status.clear();
+                    self.focus_pane(Pane::Rooms);
+                    AppReturn::Continue
+                }
+                Action::AcceptInvite => {
+                    if let Some(event) = self.state.accept_invitation() {
+                        self.dispatch(event);
+                        self.focus_pane(Pane::Rooms);
+                    }
+                    AppReturn::Continue
+                }
                 Action::SendMessage => {
-                    let target = match self.state.active_list().unwrap() {
-                        Active::Room => {
-                            let room = self.state.active_rooms.selected_item().unwrap();
-                            Target::Room(room.clone())
-                        }
-                        Active::Chat => {
-                            let user = self.state.active_chats.selected_item().unwrap();
-                            Target::Username(user.clone())
+                    let target = self.state.current_target().unwrap();
+                    if let Target::Username(username) = &target {
+                        if !self.state.can_message(username) {
+                            self.state.error = Some(format!("{username} is offline"));
+                            return AppReturn::Continue;
                         }
-                    };
+                    }
 
                     self.dispatch(IoEvent::Command(Command::Send {
                         target,
@@ -263,6 +548,8 @@ impl App {
                     }));
 
                     self.state.new_message.clear();
+                    // Typing the next message should be passed on straight away
+                    self.typing_sent = None;
                     AppReturn::Continue
                 }
                 Action::Escape => {
@@ -272,22 +559,31 @@ impl App {
                 }
             }
         } else {
-            if matches!(self.state.current_pane(), Pane::NewRoom | Pane::NewMessage) {
+            if matches!(
+                self.state.current_pane(),
+                Pane::NewRoom | Pane::NewMessage | Pane::Topic | Pane::RoomKey | Pane::Status
+            ) {
                 let input = match self.state.current_pane() {
                     Pane::NewMessage => &mut self.state.new_message,
                     Pane::NewRoom => &mut self.state.new_room,
+                    Pane::Topic => &mut self.state.new_topic,
+                    Pane::RoomKey => &mut self.state.new_key,
+                    Pane::Status => &mut self.state.new_status,
                     _ => unreachable!(),
                 };
 
-                match key {
-                    Key::Backspace => {
-                        input.pop();
-                    }
+                let changed = match key {
+                    Key::Backspace => input.pop().is_some(),
                     Key::Char(c) => {
                         input.push(c);
+                        true
                     }
-                    _ => {}
+                    _ => false,
                 };
+
+                if changed && self.state.current_pane() == Pane::NewMessage {
+                    self.typing();
+                }
             }
             AppReturn::Continue
         }
@@ -12,6 +12,14 @@ pub enum Action {
     NewRoom,
     /// Leave the selected room
     LeaveRoom,
+    /// Open modal to change the selected room's topic
+    Topic,
+    /// Make the selected room private, or public if it already is
+    Private,
+    /// Open modal of rooms we've been invited to
+    Invitations,
+    /// Open modal to set how available we are
+    Status,
     /// Focus users pane for selected room
     RoomUsers,
     /// Focus message pane
@@ -32,10 +40,26 @@ pub enum Action {
     ListNext,
     /// Start new chat with selected user of all users modal
     NewChat,
+    /// Kick selected user from the room
+    Kick,
+    /// Ban selected user from the room, or unban them if already banned
+    Ban,
+    /// Give or take operator from selected user
+    Operator,
+    /// Invite selected user of all users modal to the selected room
+    Invite,
     /// Join room of selected room from all rooms modal
     JoinRoom,
     /// Submit new room modal
     JoinOrCreateRoom,
+    /// Submit room topic modal
+    SetTopic,
+    /// Submit room key modal
+    SetPrivate,
+    /// Submit status modal
+    SetStatus,
+    /// Join room of selected invitation
+    AcceptInvite,
     /// Submit new message
     SendMessage,
     /// Escape to rooms
@@ -49,6 +73,10 @@ impl Action {
             Action::Sleep => &[Key::Ctrl('s')],
             Action::NewRoom => &[Key::Char('a')],
             Action::LeaveRoom => &[Key::Char('l')],
+            Action::Topic => &[Key::Char('t')],
+            Action::Private => &[Key::Char('P')],
+            Action::Invitations => &[Key::Char('i')],
+            Action::Status => &[Key::Char('S')],
             Action::RoomUsers => &[Key::Char('u')],
             Action::Messages => &[Key::Char('m'), Key::Enter],
             Action::MaybeFocusNewMessage => &[Key::Char('M')],
@@ -59,19 +87,31 @@ impl Action {
             Action::ListPrev => &[Key::Char('k'), Key::Up],
             Action::ListNext => &[Key::Char('j'), Key::Down],
             Action::NewChat => &[Key::Char('m'), Key::Enter],
+            Action::Kick => &[Key::Char('K')],
+            Action::Ban => &[Key::Char('B')],
+            Action::Operator => &[Key::Char('O')],
+            Action::Invite => &[Key::Char('i')],
             Action::JoinRoom => &[Key::Enter],
             Action::JoinOrCreateRoom => &[Key::Enter],
+            Action::SetTopic => &[Key::Enter],
+            Action::SetPrivate => &[Key::Enter],
+            Action::SetStatus => &[Key::Enter],
+            Action::AcceptInvite => &[Key::Enter],
             Action::SendMessage => &[Key::Enter],
             Action::Escape => &[Key::Esc],
         }
     }
 
     pub fn iterator() -> std::slice::Iter<'static, Action> {
-        static ACTIONS: [Action; 18] = [
+        static ACTIONS: [Action; 30] = [
             Action::Quit,
             Action::Sleep,
             Action::NewRoom,
             Action::LeaveRoom,
+            Action::Topic,
+            Action::Private,
+            Action::Invitations,
+            Action::Status,
             Action::RoomUsers,
             Action::Messages,
             Action::MaybeFocusNewMessage,
@@ -82,8 +122,16 @@ impl Action {
             Action::ListPrev,
             Action::ListNext,
             Action::NewChat,
+            Action::Kick,
+            Action::Ban,
+            Action::Operator,
+            Action::Invite,
             Action::JoinRoom,
             Action::JoinOrCreateRoom,
+            Action::SetTopic,
+            Action::SetPrivate,
+            Action::SetStatus,
+            Action::AcceptInvite,
             Action::SendMessage,
             Action::Escape,
         ];
@@ -103,6 +151,10 @@ impl Display for Action {
             Action::Sleep => "Sleep",
             Action::NewRoom => "Join room",
             Action::LeaveRoom => "Leave room",
+            Action::Topic => "Set topic",
+            Action::Private => "Toggle private",
+            Action::Invitations => "Invitations",
+            Action::Status => "Set status",
             Action::RoomUsers => "Room members",
             Action::Messages => "Room messages",
             Action::MaybeFocusNewMessage => "New message",
@@ -113,8 +165,16 @@ impl Display for Action {
             Action::ListPrev => "Previous",
             Action::ListNext => "Next",
             Action::NewChat => "Message user",
+            Action::Kick => "Kick",
+            Action::Ban => "Ban/Unban",
+            Action::Operator => "Toggle operator",
+            Action::Invite => "Invite to room",
             Action::JoinRoom => "Join room",
             Action::JoinOrCreateRoom => "Join/Create room",
+            Action::SetTopic => "Set topic",
+            Action::SetPrivate => "Make private",
+            Action::SetStatus => "Set status",
+            Action::AcceptInvite => "Join room",
             Action::SendMessage => "Send",
             Action::Escape => "Escape",
         };
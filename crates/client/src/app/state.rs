@@ -1,11 +1,45 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
-use common::commands::Command;
+use common::commands::{
+    Capability, ChatMessage, Command, Member, MessageId, RoomInfo, Status, Target, UserInfo,
+    Welcome,
+};
 
 use crate::{inputs::stateful_list::StatefulList, io::IoEvent};
 
 use super::Message;
 
+/// Number of older messages requested when scrolling past the top of messages
+const HISTORY_PAGE: usize = 25;
+/// How long someone shows as typing after they last said they were
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Entry in a room's users pane
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomUser {
+    Member(Member),
+    Banned(String),
+}
+
+impl RoomUser {
+    pub fn username(&self) -> &str {
+        match self {
+            RoomUser::Member(member) => &member.username,
+            RoomUser::Banned(username) => username,
+        }
+    }
+}
+
+/// Invite to a private room waiting to be accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invitation {
+    pub room: String,
+    pub by: String,
+}
+
 pub enum Active {
     Room,
     Chat,
@@ -19,6 +53,10 @@ pub enum Pane {
     NewMessage,
     Users,
     NewRoom,
+    Topic,
+    RoomKey,
+    Status,
+    Invitations,
     AllUsers,
     AllRooms,
 }
@@ -31,7 +69,11 @@ impl Pane {
             Pane::Messages => "Messages",
             Pane::NewMessage => "New Message",
             Pane::Users => "Room Users",
-            Pane::NewRoom => "New Room",
+            Pane::NewRoom => "New Room (name [key])",
+            Pane::Topic => "Room Topic",
+            Pane::RoomKey => "Room Key (empty for invite only)",
+            Pane::Status => "Status (online, away or busy, then a note)",
+            Pane::Invitations => "Invitations",
             Pane::AllUsers => "All Users",
             Pane::AllRooms => "All Rooms",
         }
@@ -43,13 +85,42 @@ pub struct State {
     keep_alive: bool,
     pub new_room: String,
     pub new_message: String,
+    pub new_topic: String,
+    pub new_key: String,
+    pub new_status: String,
     pub active_rooms: StatefulList<String>,
     pub active_chats: StatefulList<String>,
-    room_users: HashMap<String, StatefulList<String>>,
+    room_users: HashMap<String, StatefulList<RoomUser>>,
+    room_info: HashMap<String, RoomInfo>,
     room_messages: HashMap<String, StatefulList<Message>>,
+    /// Keys private rooms were joined with, to join them again if the
+    /// session is lost
+    room_keys: HashMap<String, String>,
     chat_messages: HashMap<String, StatefulList<Message>>,
-    pub all_rooms: StatefulList<String>,
-    pub all_users: StatefulList<String>,
+    pub all_rooms: StatefulList<RoomInfo>,
+    pub all_users: StatefulList<UserInfo>,
+    pub invitations: StatefulList<Invitation>,
+    pending_history: HashSet<Target>,
+    /// Direct messages we sent that are waiting for the recipient to come online
+    pub queued: HashSet<MessageId>,
+    /// Users we've had direct messages with that the server said went offline
+    pub offline: HashSet<String>,
+    /// Who's typing in each room and chat, and when they last said so
+    typing: HashMap<Target, BTreeMap<String, Instant>>,
+    /// Last message read in each room and chat, anything after it is unread
+    read: HashMap<Target, MessageId>,
+    /// Capabilities agreed on with the server
+    pub capabilities: Vec<Capability>,
+    pub server_name: String,
+    /// Who we're logged in as
+    pub username: String,
+    pub motd: Option<String>,
+    /// Secret to resume the session with if the connection drops
+    pub resume_token: Option<String>,
+    /// Whether the connection dropped and we're trying to get it back
+    pub reconnecting: bool,
+    /// Last error the server responded with
+    pub error: Option<String>,
 }
 
 impl State {
@@ -61,6 +132,18 @@ impl State {
         self.pane
     }
 
+    pub fn set_welcome(&mut self, welcome: Welcome) {
+        self.capabilities = welcome.capabilities;
+        self.server_name = welcome.server_name;
+        self.username = welcome.username;
+        self.motd = welcome.motd;
+        self.resume_token = Some(welcome.resume_token);
+
+        for room in welcome.rooms {
+            self.add_active_room(room);
+        }
+    }
+
     pub fn set_keep_alive(&mut self, keep_alive: bool) {
         self.keep_alive = keep_alive
     }
@@ -68,8 +151,6 @@ impl State {
     pub fn keep_alive(&self) -> bool {
         self.keep_alive
     }
-    
-    // TODO make sure user is removed from private chat if leaving the server
 
     pub fn unselect_lists(&mut self) {
         self.current_room_users_mut()
@@ -83,20 +164,171 @@ impl State {
     }
 
     pub fn leave_room(&mut self) -> Option<IoEvent> {
-        if let Some(room_idx) = self.active_rooms.selected() {
-            let room = self.active_rooms.items[room_idx].to_owned();
+        let room = self.active_rooms.selected_item()?.to_owned();
+        self.remove_room(&room);
+        Some(IoEvent::Command(Command::Leave { room }))
+    }
 
-            self.active_rooms.previous();
+    /// Forget about a room we're no longer in
+    pub fn remove_room(&mut self, room: &str) {
+        if let Some(room_idx) = self.active_rooms.items.iter().position(|r| r == room) {
+            if self.active_rooms.selected() == Some(room_idx) {
+                self.active_rooms.previous();
+            }
             self.active_rooms.items.remove(room_idx);
-            self.room_users.remove(&room);
-            self.room_messages.remove(&room);
+            self.room_users.remove(room);
+            self.room_info.remove(room);
+            self.room_messages.remove(room);
+            self.room_keys.remove(room);
+        }
+    }
+
+    /// Join or create `room`, remembering its key in case we have to join
+    /// it again
+    pub fn join_room(&mut self, room: String, key: Option<String>) -> IoEvent {
+        if let Some(key) = &key {
+            self.room_keys.insert(room.clone(), key.clone());
+        }
+        IoEvent::Command(Command::JoinOrCreate { room, key })
+    }
+
+    /// Key `room` was joined with, if it needed one
+    pub fn room_key(&self, room: &str) -> Option<String> {
+        self.room_keys.get(room).cloned()
+    }
+
+    /// Replace the users of a room, ordered by role with banned users last
+    pub fn set_room_users(&mut self, room: &str, mut users: Vec<Member>, mut banned: Vec<String>) {
+        users.sort_by(|a, b| {
+            b.role
+                .cmp(&a.role)
+                .then_with(|| a.username.cmp(&b.username))
+        });
+        banned.sort();
+
+        if let Some(list) = self.room_users.get_mut(room) {
+            list.items = users
+                .into_iter()
+                .map(RoomUser::Member)
+                .chain(banned.into_iter().map(RoomUser::Banned))
+                .collect();
+        }
+    }
+
+    /// Update someone's status wherever they're listed
+    pub fn set_status(&mut self, username: &str, status: Status, note: Option<String>) {
+        if let Some(user) = self
+            .all_users
+            .items
+            .iter_mut()
+            .find(|u| u.username == username)
+        {
+            user.status = status;
+            user.note = note;
+        }
+
+        for list in self.room_users.values_mut() {
+            for user in &mut list.items {
+                if let RoomUser::Member(member) = user {
+                    if member.username == username {
+                        member.status = status;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_room_info(&mut self, info: RoomInfo) {
+        self.room_info.insert(info.name.clone(), info);
+    }
+
+    /// Topic and privacy of the selected room
+    pub fn current_room_info(&self) -> Option<&RoomInfo> {
+        match self.active_list()? {
+            Active::Room => self.room_info.get(self.active_rooms.selected_item()?),
+            Active::Chat => None,
+        }
+    }
+
+    pub fn current_topic(&self) -> Option<&str> {
+        self.current_room_info()?.topic.as_deref()
+    }
+
+    pub fn add_invitation(&mut self, invitation: Invitation) {
+        let invitations = &mut self.invitations.items;
+        if !invitations.iter().any(|i| i.room == invitation.room) {
+            invitations.push(invitation);
+        }
+    }
+
+    /// Join the room of the selected invitation
+    pub fn accept_invitation(&mut self) -> Option<IoEvent> {
+        let idx = self.invitations.selected()?;
+        let invitation = self.invitations.items.remove(idx);
+        self.invitations.unselect();
+
+        Some(IoEvent::Command(Command::JoinOrCreate {
+            room: invitation.room,
+            key: None,
+        }))
+    }
 
-            Some(IoEvent::Command(Command::Leave { room }))
+    /// The room selected and the user selected in its users pane
+    pub fn selected_room_user(&mut self) -> Option<(String, RoomUser)> {
+        let user = self.current_room_users_mut()?.selected_item()?.clone();
+        let room = self.active_rooms.selected_item()?.clone();
+        Some((room, user))
+    }
+
+    /// Request the page of messages before the oldest one of the selected room/chat,
+    /// unless a request for it is already in flight
+    pub fn fetch_history(&mut self) -> Option<IoEvent> {
+        if !self.supports(Capability::History) {
+            return None;
+        }
+
+        let target = self.current_target()?;
+        let messages = self.current_messages_mut()?;
+        let before = messages.items.iter().find_map(Message::id);
+
+        if self.pending_history.insert(target.clone()) {
+            Some(IoEvent::Command(Command::FetchHistory {
+                target,
+                before,
+                limit: HISTORY_PAGE,
+            }))
         } else {
             None
         }
     }
 
+    /// Add older messages to the top of a room/chat, keeping the selected message the same
+    pub fn prepend_history(&mut self, target: Target, messages: Vec<ChatMessage>) {
+        self.pending_history.remove(&target);
+
+        let list = match &target {
+            Target::Room(room) => self.room_messages.get_mut(room),
+            Target::Username(username) => self.chat_messages.get_mut(username),
+        };
+
+        if let Some(list) = list {
+            let count = messages.len();
+            list.items
+                .splice(0..0, messages.into_iter().map(Message::Chat));
+
+            if let Some(selected) = list.selected() {
+                list.state.select(Some(selected + count));
+            }
+        }
+    }
+
+    pub fn current_target(&self) -> Option<Target> {
+        match self.active_list()? {
+            Active::Room => Some(Target::Room(self.active_rooms.selected_item()?.clone())),
+            Active::Chat => Some(Target::Username(self.active_chats.selected_item()?.clone())),
+        }
+    }
+
     pub fn active_list(&self) -> Option<Active> {
         if self.active_rooms.selected_item().is_some() {
             Some(Active::Room)
@@ -107,11 +339,7 @@ impl State {
         }
     }
 
-    pub fn room_users_mut(&mut self, room: &str) -> Option<&mut StatefulList<String>> {
-        self.room_users.get_mut(room)
-    }
-
-    pub fn current_room_users_mut(&mut self) -> Option<&mut StatefulList<String>> {
+    pub fn current_room_users_mut(&mut self) -> Option<&mut StatefulList<RoomUser>> {
         if let Active::Room = self.active_list()? {
             let selected = self.active_rooms.selected_item()?;
             self.room_users.get_mut(selected)
@@ -158,6 +386,104 @@ impl State {
         }
     }
 
+    /// Forget every message, keeping the rooms and chats they were in
+    pub fn clear_messages(&mut self) {
+        let lists = self
+            .room_messages
+            .values_mut()
+            .chain(self.chat_messages.values_mut());
+        for list in lists {
+            *list = StatefulList::with_items(vec![]);
+        }
+        self.pending_history.clear();
+        self.queued.clear();
+    }
+
+    /// Note everything up to `id` has been read, from this or another session
+    pub fn set_read(&mut self, target: Target, id: MessageId) {
+        let read = self.read.entry(target).or_insert(id);
+        *read = (*read).max(id);
+    }
+
+    /// Number of messages from others after the last one read, only counting
+    /// those that have been loaded
+    pub fn unread(&self, target: &Target) -> usize {
+        let messages = match target {
+            Target::Room(room) => self.room_messages.get(room),
+            Target::Username(username) => self.chat_messages.get(username),
+        };
+        let read = self.read.get(target);
+
+        let unread = messages.into_iter().flat_map(|list| &list.items);
+        unread
+            .filter(|m| match m {
+                Message::Chat(m) => m.sender != self.username && read.is_none_or(|r| m.id > *r),
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Mark everything in the selected room or chat as read, if anything new
+    /// arrived since it last was
+    pub fn mark_current_read(&mut self) -> Option<IoEvent> {
+        if !self.supports(Capability::ReadMarkers) {
+            return None;
+        }
+
+        let target = self.current_target()?;
+        let messages = self.current_messages_mut()?;
+        let last = messages.items.iter().rev().find_map(Message::id)?;
+        if self.read.get(&target).is_some_and(|read| *read >= last) {
+            return None;
+        }
+
+        self.read.insert(target.clone(), last);
+        Some(IoEvent::Command(Command::MarkRead {
+            target,
+            message_id: last,
+        }))
+    }
+
+    pub fn set_typing(&mut self, target: Target, username: String) {
+        let typing = self.typing.entry(target).or_default();
+        typing.insert(username, Instant::now());
+    }
+
+    /// Stop showing `username` as typing, once their message arrives
+    pub fn stop_typing(&mut self, target: &Target, username: &str) {
+        if let Some(typing) = self.typing.get_mut(target) {
+            typing.remove(username);
+        }
+    }
+
+    /// Forget anyone who hasn't said they're typing in a while
+    pub fn expire_typing(&mut self) {
+        for typing in self.typing.values_mut() {
+            typing.retain(|_, at| at.elapsed() < TYPING_TIMEOUT);
+        }
+    }
+
+    /// Who's typing in the selected room or chat
+    pub fn current_typing(&self) -> Vec<&str> {
+        let typing = self.current_target().and_then(|t| self.typing.get(&t));
+        typing
+            .into_iter()
+            .flat_map(BTreeMap::keys)
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether a direct message can be sent to `username` right now, offline
+    /// users can only be messaged if the server holds on to it for them
+    pub fn can_message(&self, username: &str) -> bool {
+        !self.offline.contains(username) || self.supports(Capability::OfflineDelivery)
+    }
+
+    /// Whether the server agreed to `capability` when we logged in
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
     pub fn add_chat(&mut self, username: String) {
         if !self.active_chats.items.contains(&username) {
             self.active_chats.items.push(username.clone());
@@ -181,13 +507,31 @@ impl Default for State {
             keep_alive: true,
             new_room: String::from(""),
             new_message: String::from(""),
+            new_topic: String::from(""),
+            new_key: String::from(""),
+            new_status: String::from(""),
             active_rooms: StatefulList::default(),
             active_chats: StatefulList::default(),
             room_users: HashMap::default(),
+            room_info: HashMap::default(),
             room_messages: HashMap::default(),
+            room_keys: HashMap::default(),
             chat_messages: HashMap::default(),
-            all_rooms: StatefulList::default(),
-            all_users: StatefulList::default(),
+            all_rooms: StatefulList::with_items(vec![]),
+            all_users: StatefulList::with_items(vec![]),
+            invitations: StatefulList::with_items(vec![]),
+            pending_history: HashSet::default(),
+            queued: HashSet::default(),
+            offline: HashSet::default(),
+            typing: HashMap::default(),
+            read: HashMap::default(),
+            capabilities: Vec::default(),
+            server_name: String::from(""),
+            username: String::from(""),
+            motd: None,
+            resume_token: None,
+            reconnecting: false,
+            error: None,
         }
     }
 }
@@ -1,27 +1,64 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
+use chrono::Utc;
 use common::{
     client::Client,
-    commands::{Command, Response},
-    Result,
+    commands::{Command, Password, Response, ResponseError, RoomInfo, Target, Welcome},
+    tls::TlsConnector,
+    Error, Result,
 };
 use tokio::sync::Mutex;
 
-use crate::app::App;
+use crate::app::{state::Invitation, App, Message};
+
+/// First wait before trying to reconnect, doubled after each failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 pub enum IoEvent {
     Sleep,
     Command(Command),
+    /// The server stopped keeping alive, drop the connection and resume
+    Reconnect,
+}
+
+/// Where the server is and how to connect to it, kept to reconnect with
+#[derive(Clone)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    /// Name the server's tls certificate has to be valid for
+    pub domain: String,
+    pub tls: Option<TlsConnector>,
+    pub username: String,
+    /// Logged in with again if the server lost the session
+    pub password: Password,
+}
+
+impl Endpoint {
+    pub async fn connect(&self) -> Result<Client> {
+        match &self.tls {
+            Some(connector) => {
+                let connector = connector.clone();
+                Client::connect_tls(self.addr, &self.domain, connector, self.username.clone()).await
+            }
+            None => Client::connect(self.addr, self.username.clone()).await,
+        }
+    }
 }
 
 pub struct IoHandler {
     client: Client,
+    endpoint: Endpoint,
     app: Arc<Mutex<App>>,
 }
 
 impl IoHandler {
-    pub fn new(client: Client, app: Arc<Mutex<App>>) -> Self {
-        Self { client, app }
+    pub fn new(client: Client, endpoint: Endpoint, app: Arc<Mutex<App>>) -> Self {
+        Self {
+            client,
+            endpoint,
+            app,
+        }
     }
 
     pub async fn read_response(&mut self) -> Result<Response> {
@@ -30,10 +67,32 @@ impl IoHandler {
 
     pub async fn handle_response(&mut self, response: Response) {
         match response {
-            Response::ListMembers { room, users } => {
+            Response::Welcome(welcome) => {
+                let mut app = self.app.lock().await;
+                app.state.set_welcome(welcome);
+                app.session_started();
+            }
+            Response::ListMembers {
+                room,
+                topic,
+                private,
+                users,
+                banned,
+            } => {
                 let mut app = self.app.lock().await;
                 app.state.add_active_room(room.clone());
-                app.state.room_users_mut(&room).unwrap().items = users;
+                app.state.set_room_users(&room, users, banned);
+                app.state.set_room_info(RoomInfo {
+                    name: room,
+                    topic,
+                    private,
+                });
+            }
+            Response::Kicked { room, by, banned } => {
+                let mut app = self.app.lock().await;
+                app.state.remove_room(&room);
+                let action = if banned { "Banned" } else { "Kicked" };
+                app.state.error = Some(format!("{action} from {room} by {by}"));
             }
             Response::ListUsers { users } => {
                 let mut app = self.app.lock().await;
@@ -43,55 +102,221 @@ impl IoHandler {
                 let mut app = self.app.lock().await;
                 app.state.all_rooms.items = rooms;
             }
-            Response::TellRoom {
-                room,
-                sender,
-                message,
-            } => {
+            Response::TellRoom { room, message } => {
                 let mut app = self.app.lock().await;
+                let target = Target::Room(room.clone());
+                app.state.stop_typing(&target, &message.sender);
                 app.state.room_messages_mut(&room)
                     .unwrap()
                     .items
-                    .push((sender, message));
+                    .push(Message::Chat(message));
             }
-            Response::TellUser {
+            Response::UserJoined { room, username } => {
+                let mut app = self.app.lock().await;
+                if let Some(messages) = app.state.room_messages_mut(&room) {
+                    messages.items.push(Message::Joined {
+                        timestamp: Utc::now(),
+                        username,
+                    });
+                }
+            }
+            Response::UserLeft {
+                room,
                 username,
-                sender,
-                message,
+                reason,
             } => {
                 let mut app = self.app.lock().await;
-                if self.client.username() == sender {
-                    if app.state.chat_messages_mut(&username).is_none() {
-                        app.state.add_chat(username.clone());
-                    }
-
-                    app.state.chat_messages_mut(&username)
-                        .unwrap()
-                        .items
-                        .push((sender, message));
+                // We left from another session
+                if username == self.client.username() {
+                    app.state.remove_room(&room);
+                } else if let Some(messages) = app.state.room_messages_mut(&room) {
+                    messages.items.push(Message::Left {
+                        timestamp: Utc::now(),
+                        username,
+                        reason,
+                    });
+                }
+            }
+            Response::TellUser { username, message } => {
+                let mut app = self.app.lock().await;
+                // Messages we sent are filed under the recipient, otherwise under the sender
+                let chat = if self.client.username() == message.sender {
+                    username
                 } else {
-                    if app.state.chat_messages_mut(&sender).is_none() {
-                        app.state.add_chat(sender.clone());
-                    }
+                    message.sender.clone()
+                };
+
+                let target = Target::Username(chat.clone());
+                app.state.stop_typing(&target, &message.sender);
 
-                    app.state.chat_messages_mut(&sender)
-                        .unwrap()
-                        .items
-                        .push((sender, message));
+                if app.state.chat_messages_mut(&chat).is_none() {
+                    app.state.add_chat(chat.clone());
+                }
+
+                app.state.chat_messages_mut(&chat)
+                    .unwrap()
+                    .items
+                    .push(Message::Chat(message));
+            }
+            Response::History { target, messages } => {
+                let mut app = self.app.lock().await;
+                app.state.prepend_history(target, messages);
+            }
+            Response::Queued { ids, .. } => {
+                let mut app = self.app.lock().await;
+                app.state.queued.extend(ids);
+            }
+            Response::Delivered { ids, .. } => {
+                let mut app = self.app.lock().await;
+                for id in ids {
+                    app.state.queued.remove(&id);
                 }
             }
+            Response::Presence { username, online } => {
+                let mut app = self.app.lock().await;
+                if online {
+                    app.state.offline.remove(&username);
+                } else {
+                    app.state.offline.insert(username);
+                }
+            }
+            Response::MarkedRead { target, message_id } => {
+                let mut app = self.app.lock().await;
+                app.state.set_read(target, message_id);
+            }
+            Response::Typing { target, username } => {
+                let mut app = self.app.lock().await;
+                app.state.set_typing(target, username);
+            }
+            Response::StatusChanged {
+                username,
+                status,
+                note,
+            } => {
+                let mut app = self.app.lock().await;
+                app.state.set_status(&username, status, note);
+            }
+            Response::Invited { room, by } => {
+                let mut app = self.app.lock().await;
+                app.state.add_invitation(Invitation { room, by });
+            }
             Response::KeepAlive => {
                 let mut app = self.app.lock().await;
                 app.state.set_keep_alive(true);
             }
-            Response::Err(_) => todo!(),
+            Response::Err(err) => {
+                let mut app = self.app.lock().await;
+                app.state.error = Some(err.to_string());
+            }
         }
     }
 
-    pub async fn handle_io(&mut self, event: IoEvent) {
+    /// Returns false if the connection was lost for good
+    pub async fn handle_io(&mut self, event: IoEvent) -> bool {
         match event {
             IoEvent::Sleep => self.handle_sleep().await,
-            IoEvent::Command(command) => self.client.write_command(command).await.unwrap(),
+            IoEvent::Command(command) => {
+                if self.client.write_command(command.clone()).await.is_err() {
+                    if !self.reconnect().await {
+                        return false;
+                    }
+                    // Nothing was sent on the dead connection so try again on the new one
+                    return self.client.write_command(command).await.is_ok();
+                }
+            }
+            IoEvent::Reconnect => {
+                // Already reconnected if another drop was noticed first
+                if self.app.lock().await.state.reconnecting {
+                    return self.reconnect().await;
+                }
+            }
+        }
+        true
+    }
+
+    /// Connect again after the connection dropped, resuming the session so
+    /// rooms and anything sent in the meantime aren't lost. If the server no
+    /// longer knows the session, log in again and rejoin the rooms we were in.
+    /// Returns false if the server won't have us back
+    pub async fn reconnect(&mut self) -> bool {
+        let token = {
+            let mut app = self.app.lock().await;
+            app.state.reconnecting = true;
+            app.state.resume_token.clone()
+        };
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut resumable = token.is_some();
+        loop {
+            let result = if resumable {
+                self.resume(token.clone().unwrap_or_default()).await
+            } else {
+                self.hello().await
+            };
+
+            match result {
+                Ok(welcome) => {
+                    let mut app = self.app.lock().await;
+                    if !resumable {
+                        Self::rejoin(&mut app, &welcome.rooms);
+                    }
+                    app.state.set_welcome(welcome);
+                    app.state.set_keep_alive(true);
+                    app.state.reconnecting = false;
+                    return true;
+                }
+                // Expired or the server restarted, try again straight away
+                Err(Error::Rejected(ResponseError::InvalidResumeToken)) if resumable => {
+                    resumable = false;
+                }
+                Err(Error::Rejected(err)) => {
+                    let mut app = self.app.lock().await;
+                    app.state.error = Some(err.to_string());
+                    app.state.reconnecting = false;
+                    return false;
+                }
+                Err(_) => {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    async fn resume(&mut self, token: String) -> Result<Welcome> {
+        let mut client = self.endpoint.connect().await?;
+        client.resume(token).await?;
+        let welcome = client.welcome().await?;
+
+        self.client = client;
+        Ok(welcome)
+    }
+
+    async fn hello(&mut self) -> Result<Welcome> {
+        let mut client = self.endpoint.connect().await?;
+        client.hello(self.endpoint.password.clone()).await?;
+        let welcome = client.welcome().await?;
+
+        self.client = client;
+        Ok(welcome)
+    }
+
+    /// Join every room we were in that the new session isn't in already.
+    /// Messages are cleared since the server sends recent history again, for
+    /// rooms we're still in along with the welcome and the rest once joined
+    fn rejoin(app: &mut App, joined: &[String]) {
+        app.state.clear_messages();
+        let rooms: Vec<_> = app
+            .state
+            .active_rooms
+            .items
+            .iter()
+            .filter(|room| !joined.contains(room))
+            .cloned()
+            .collect();
+        for room in rooms {
+            let key = app.state.room_key(&room);
+            app.dispatch(IoEvent::Command(Command::JoinOrCreate { room, key }));
         }
     }
 
@@ -101,3 +326,41 @@ impl IoHandler {
         println!("Done sleeping")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::commands::ChatMessage;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[test]
+    fn rejoin_rooms_the_new_session_is_not_in() {
+        let (io_tx, mut io_rx) = unbounded_channel();
+        let mut app = App::new(io_tx, None);
+        app.state.join_room("secret".into(), Some("hunter2".into()));
+        for room in ["kept", "secret"] {
+            app.state.add_active_room(room.into());
+            let message = ChatMessage {
+                id: 1,
+                timestamp: Utc::now(),
+                sender: "alice".into(),
+                message: "hi".into(),
+            };
+            let messages = app.state.room_messages_mut(room).unwrap();
+            messages.items.push(Message::Chat(message));
+        }
+
+        IoHandler::rejoin(&mut app, &["kept".into()]);
+
+        for room in ["kept", "secret"] {
+            assert!(app.state.room_messages_mut(room).unwrap().items.is_empty());
+        }
+        let Ok(IoEvent::Command(Command::JoinOrCreate { room, key })) = io_rx.try_recv() else {
+            panic!("expected a join");
+        };
+        assert_eq!(room, "secret");
+        assert_eq!(key.as_deref(), Some("hunter2"));
+        assert!(io_rx.try_recv().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     error::Error,
     io::{self, stdout},
     net::ToSocketAddrs,
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
@@ -10,12 +11,13 @@ use clap::Parser;
 use client::{
     app::{App, AppReturn},
     inputs::{Events, InputEvent},
-    io::{IoEvent, IoHandler},
+    io::{Endpoint, IoEvent, IoHandler},
     ui,
 };
 use common::{
     client::Client,
-    commands::{Command, KEEP_ALIVE_CHECK, KEEP_ALIVE_INTERVAL},
+    commands::{Command, Password},
+    tls,
 };
 use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
 use tokio::sync::{
@@ -37,36 +39,109 @@ struct Args {
     /// port
     #[arg(short, default_value = "4000")]
     port: u16,
+    /// file containing the account password, prompted for if not given
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+    /// create a new account instead of logging in
+    #[arg(long)]
+    register: bool,
+    /// connect to the server over tls
+    #[arg(long)]
+    tls: bool,
+    /// pem file of certificates to trust instead of the web pki roots, implies --tls
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// seconds without a key press before this session counts as idle, 0 to never
+    #[arg(long, default_value = "300")]
+    away_after: u64,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
-    let addr = (args.host, args.port)
+    let addr = (args.host.as_str(), args.port)
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
 
-    let client = Client::connect(addr, args.user.clone()).await?;
+    let password = match &args.password_file {
+        Some(path) => std::fs::read_to_string(path)?.trim_end().to_owned(),
+        None => rpassword::prompt_password(format!("Password for {}: ", args.user))?,
+    };
+
+    let tls = if args.tls || args.tls_ca.is_some() {
+        Some(tls::connector(args.tls_ca.as_deref())?)
+    } else {
+        None
+    };
+    let endpoint = Endpoint {
+        addr,
+        domain: args.host.clone(),
+        tls,
+        username: args.user.clone(),
+        password: Password(password),
+    };
+
+    let mut client = endpoint.connect().await?;
+    let over_tls = if endpoint.tls.is_some() {
+        " over tls"
+    } else {
+        ""
+    };
+    println!("Connected to server at {addr}{over_tls}");
+    if args.register {
+        client.register(endpoint.password.clone()).await?;
+    } else {
+        client.hello(endpoint.password.clone()).await?;
+    }
+
+    // Don't draw anything until the server has accepted us
+    let welcome = match client.welcome().await {
+        Ok(welcome) => welcome,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let username = welcome.username.clone();
+    let keep_alive_interval = Duration::from_secs(welcome.keep_alive_interval);
+    let keep_alive_timeout = Duration::from_secs(welcome.keep_alive_timeout);
+
     let (io_tx, io_rx) = unbounded_channel();
 
-    let app = App::new(io_tx);
+    let away_after = (args.away_after > 0).then(|| Duration::from_secs(args.away_after));
+    let mut app = App::new(io_tx, away_after);
+    app.state.set_welcome(welcome);
     let app = Arc::new(Mutex::new(app));
 
     set_panic();
-    start_io(client, app.clone(), io_rx).await;
-    start_ui(app, args.user).await
+    start_io(
+        client,
+        endpoint,
+        app.clone(),
+        io_rx,
+        keep_alive_interval,
+        keep_alive_timeout,
+    )
+    .await;
+    start_ui(app, username).await
 }
 
-async fn start_io(mut client: Client, app: Arc<Mutex<App>>, mut io_rx: UnboundedReceiver<IoEvent>) {
-    client.hello().await.unwrap();
-    let mut io_handler = IoHandler::new(client, app.clone());
+async fn start_io(
+    client: Client,
+    endpoint: Endpoint,
+    app: Arc<Mutex<App>>,
+    mut io_rx: UnboundedReceiver<IoEvent>,
+    keep_alive_interval: Duration,
+    keep_alive_timeout: Duration,
+) {
+    let mut io_handler = IoHandler::new(client, endpoint, app.clone());
 
     // Send keep alive
     let keep_alive_app = app.clone();
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(Duration::from_secs(KEEP_ALIVE_INTERVAL)).await;
+            tokio::time::sleep(keep_alive_interval).await;
 
             let mut app = keep_alive_app.lock().await;
             // Comment out to see server kill connection if it doesn't get keep alive
@@ -77,13 +152,16 @@ async fn start_io(mut client: Client, app: Arc<Mutex<App>>, mut io_rx: Unbounded
     // Check keep alive
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(Duration::from_secs(KEEP_ALIVE_CHECK)).await;
+            tokio::time::sleep(keep_alive_timeout).await;
 
             let mut app = app.lock().await;
+            if app.state.reconnecting {
+                continue;
+            }
             if !app.state.keep_alive() {
-                reset_terminal().unwrap();
-                eprintln!("Server shutdown, quitting...");
-                std::process::exit(1);
+                // The connection is dead even if the socket hasn't noticed yet
+                app.state.reconnecting = true;
+                app.dispatch(IoEvent::Reconnect);
             } else {
                 app.state.set_keep_alive(false);
             }
@@ -94,14 +172,21 @@ async fn start_io(mut client: Client, app: Arc<Mutex<App>>, mut io_rx: Unbounded
         loop {
             tokio::select! {
                 maybe_response = io_handler.read_response() => {
-                    let response = match maybe_response {
-                        Ok(response) => response,
-                        Err(_err) => break
+                    match maybe_response {
+                        Ok(response) => io_handler.handle_response(response).await,
+                        // Something from a newer server, the connection's still fine
+                        Err(common::Error::InvalidResponse(_)) => {}
+                        Err(_err) => {
+                            if !io_handler.reconnect().await {
+                                break;
+                            }
+                        }
                     };
-                    io_handler.handle_response(response).await;
                 }
                 Some(event) = io_rx.recv() => {
-                    io_handler.handle_io(event).await;
+                    if !io_handler.handle_io(event).await {
+                        break;
+                    }
                 }
             };
         }
@@ -1,3 +1,4 @@
+use chrono::Local;
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -7,7 +8,13 @@ use tui::{
     Frame,
 };
 
-use crate::app::{actions::Actions, state::Pane, App};
+use common::commands::{LeaveReason, Role, RoomInfo, Status, Target, UserInfo};
+
+use crate::app::{
+    actions::Actions,
+    state::{Active, Pane, RoomUser},
+    App, Message,
+};
 
 pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
     let size = rect.size();
@@ -19,6 +26,8 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         .title(Spans::from(vec![
             Span::from("IRC as "),
             current_user_span(username),
+            Span::from(format!(" on {}", app.state.server_name)),
+            reconnecting_span(app.state.reconnecting),
         ]))
         .title_alignment(Alignment::Center)
         .border_type(BorderType::Rounded);
@@ -30,7 +39,7 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         .constraints([Constraint::Min(15), Constraint::Length(4)])
         .split(size);
 
-    let action_menu = actions_menu(app.current_actions());
+    let action_menu = actions_menu(app.current_actions(), app.state.error.as_deref());
     rect.render_widget(action_menu, app_chunks[1]);
 
     let chunks = Layout::default()
@@ -56,11 +65,20 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         .active_rooms
         .items
         .iter()
-        .map(|i| ListItem::new(Span::from(i.as_ref())))
+        .map(|i| {
+            let unread = app.state.unread(&Target::Room(i.clone()));
+            let spans = vec![Span::from(i.as_str()), unread_span(unread)];
+            ListItem::new(Spans::from(spans))
+        })
         .collect();
 
+    let mut rooms_block = panel(Pane::Rooms, app.state.current_pane());
+    let invitations = app.state.invitations.items.len();
+    if invitations > 0 {
+        rooms_block = rooms_block.title(format!("{} ({invitations} invites)", Pane::Rooms.title()));
+    }
     let active_rooms = List::new(active_rooms)
-        .block(panel(Pane::Rooms, app.state.current_pane()))
+        .block(rooms_block)
         .highlight_style(Style::default().fg(Color::LightBlue))
         .highlight_symbol("> ");
     rect.render_stateful_widget(
@@ -75,7 +93,10 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         .active_chats
         .items
         .iter()
-        .map(|i| ListItem::new(Span::from(i.as_ref())))
+        .map(|i| {
+            let unread = app.state.unread(&Target::Username(i.clone()));
+            chat_list_item(i, app.state.offline.contains(i), unread)
+        })
         .collect();
 
     let active_chats = List::new(active_chats)
@@ -91,17 +112,39 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
     // Messages
     let message_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(85), Constraint::Percentage(15)])
+        .constraints([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Percentage(15),
+        ])
         .split(chunks[1]);
 
-    let messages_block = panel(Pane::Messages, app.state.current_pane());
+    let typing = typing_line(&app.state.current_typing());
+    rect.render_widget(typing, message_chunks[1]);
+
+    let mut messages_block = panel(Pane::Messages, app.state.current_pane());
+    if let Some(topic) = app.state.current_topic() {
+        messages_block = messages_block.title(format!("{} - {topic}", Pane::Messages.title()));
+    }
     let new_message_block = panel(Pane::NewMessage, app.state.current_pane());
 
+    // Direct messages we sent show whether they've reached the recipient yet
+    let in_chat = matches!(app.state.active_list(), Some(Active::Chat));
+    let queued = app.state.queued.clone();
+
     if let Some(messages) = app.state.current_messages_mut() {
         let message_items: Vec<_> = messages
             .items
             .iter()
-            .map(|m| message_list_item(m, username))
+            .map(|m| {
+                let delivered = match m {
+                    Message::Chat(m) if in_chat && m.sender == username => {
+                        Some(!queued.contains(&m.id))
+                    }
+                    _ => None,
+                };
+                message_list_item(m, username, delivered)
+            })
             .collect();
 
         let message_list = List::new(message_items)
@@ -113,10 +156,14 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         let message_input = Paragraph::new(app.state.new_message.as_str())
             .block(new_message_block)
             .wrap(Wrap { trim: false });
-        rect.render_widget(message_input, message_chunks[1]);
+        rect.render_widget(message_input, message_chunks[2]);
     } else {
-        rect.render_widget(messages_block, message_chunks[0]);
-        rect.render_widget(new_message_block, message_chunks[1]);
+        // Nothing selected yet, show the message of the day
+        let motd = Paragraph::new(app.state.motd.as_deref().unwrap_or_default())
+            .block(messages_block)
+            .wrap(Wrap { trim: false });
+        rect.render_widget(motd, message_chunks[0]);
+        rect.render_widget(new_message_block, message_chunks[2]);
     }
 
     // Room Users
@@ -125,7 +172,7 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
         let list_items: Vec<_> = room_users
             .items
             .iter()
-            .map(|i| user_list_item(i, username))
+            .map(|i| room_user_list_item(i, username))
             .collect();
 
         let list = List::new(list_items)
@@ -146,6 +193,46 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
             rect.render_widget(Clear, area);
             rect.render_widget(input, area);
         }
+        Pane::Topic => {
+            let block = panel(Pane::Topic, app.state.current_pane());
+            let area = centered_rect(60, 12, size);
+            let input = Paragraph::new(app.state.new_topic.as_str()).block(block);
+            rect.render_widget(Clear, area);
+            rect.render_widget(input, area);
+        }
+        Pane::RoomKey => {
+            let block = panel(Pane::RoomKey, app.state.current_pane());
+            let area = centered_rect(60, 12, size);
+            let input = Paragraph::new(app.state.new_key.as_str()).block(block);
+            rect.render_widget(Clear, area);
+            rect.render_widget(input, area);
+        }
+        Pane::Status => {
+            let block = panel(Pane::Status, app.state.current_pane());
+            let area = centered_rect(60, 12, size);
+            let input = Paragraph::new(app.state.new_status.as_str()).block(block);
+            rect.render_widget(Clear, area);
+            rect.render_widget(input, area);
+        }
+        Pane::Invitations => {
+            let area = centered_rect(45, 30, size);
+            rect.render_widget(Clear, area);
+
+            let invitations: Vec<ListItem> = app
+                .state
+                .invitations
+                .items
+                .iter()
+                .map(|i| ListItem::new(format!("{} from {}", i.room, i.by)))
+                .collect();
+
+            let invitations = List::new(invitations)
+                .block(panel(Pane::Invitations, app.state.current_pane()))
+                .highlight_style(Style::default().fg(Color::LightBlue))
+                .highlight_symbol("> ");
+
+            rect.render_stateful_widget(invitations, area, &mut app.state.invitations.state);
+        }
         Pane::AllUsers => {
             let area = centered_rect(45, 30, size);
             rect.render_widget(Clear, area);
@@ -174,7 +261,7 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
                 .all_rooms
                 .items
                 .iter()
-                .map(|i| ListItem::new(Span::from(i.as_ref())))
+                .map(room_info_list_item)
                 .collect();
 
             let all_rooms = List::new(all_rooms)
@@ -188,7 +275,7 @@ pub fn draw<B: Backend>(rect: &mut Frame<B>, app: &mut App, username: &str) {
     }
 }
 
-fn actions_menu(actions: &Actions) -> Paragraph {
+fn actions_menu<'a>(actions: &'a Actions, error: Option<&'a str>) -> Paragraph<'a> {
     let mut spans: Vec<Span> = vec![];
 
     let mut iter = actions.actions().iter();
@@ -200,36 +287,183 @@ fn actions_menu(actions: &Actions) -> Paragraph {
             spans.push(Span::from(action.display_with_keys()));
         }
     }
+    let mut block = Block::default().borders(Borders::ALL);
+    if let Some(error) = error {
+        block = block.title(Span::styled(error, Style::default().fg(Color::Red)));
+    }
+
     Paragraph::new(Spans::from(spans))
-        .block(Block::default().borders(Borders::ALL))
+        .block(block)
         .wrap(Wrap { trim: true })
 }
 
-fn message_list_item<'a>(current: &'a (String, String), username: &'a str) -> ListItem<'a> {
-    let sender_span = if current.0 == username {
+fn message_list_item<'a>(
+    current: &'a Message,
+    username: &'a str,
+    delivered: Option<bool>,
+) -> ListItem<'a> {
+    let timestamp = current.timestamp().with_timezone(&Local).format("%H:%M");
+    let mut spans = vec![Span::styled(
+        format!("[{timestamp}] "),
+        Style::default().fg(Color::DarkGray),
+    )];
+
+    // Joins and leaves are greyed out so they don't read as chat
+    let event_style = Style::default()
+        .fg(Color::DarkGray)
+        .add_modifier(Modifier::ITALIC);
+    let message = match current {
+        Message::Chat(message) => message,
+        Message::Joined { username, .. } => {
+            spans.push(Span::styled(format!("→ {username} joined"), event_style));
+            return ListItem::new(Spans::from(spans));
+        }
+        Message::Left {
+            username, reason, ..
+        } => {
+            let reason = match reason {
+                LeaveReason::Left => "left",
+                LeaveReason::Kicked => "was kicked",
+                LeaveReason::Banned => "was banned",
+                LeaveReason::TimedOut => "timed out",
+                LeaveReason::Disconnected => "disconnected",
+            };
+            spans.push(Span::styled(format!("← {username} {reason}"), event_style));
+            return ListItem::new(Spans::from(spans));
+        }
+    };
+
+    let sender_span = if message.sender == username {
         current_user_span(username)
     } else {
-        Span::from(current.0.as_str())
+        Span::from(message.sender.as_str())
     };
+    spans.push(sender_span);
+    spans.push(Span::from(format!(": {}", message.message)));
+
+    if let Some(delivered) = delivered {
+        let status = if delivered { " ✓" } else { " (queued)" };
+        spans.push(Span::styled(status, Style::default().fg(Color::DarkGray)));
+    }
 
-    ListItem::new(Spans::from(vec![
-        sender_span,
-        Span::from(format!(": {}", current.1)),
-    ]))
+    ListItem::new(Spans::from(spans))
 }
 
-fn user_list_item<'a>(current: &'a str, username: &'a str) -> ListItem<'a> {
-    if current == username {
-        ListItem::new(current_user_span(username))
+fn typing_line<'a>(typing: &[&str]) -> Paragraph<'a> {
+    let text = match typing {
+        [] => String::new(),
+        [user] => format!(" {user} is typing…"),
+        [first, second] => format!(" {first} and {second} are typing…"),
+        _ => String::from(" several people are typing…"),
+    };
+    let style = Style::default()
+        .fg(Color::DarkGray)
+        .add_modifier(Modifier::ITALIC);
+    Paragraph::new(Span::styled(text, style))
+}
+
+fn user_list_item<'a>(current: &'a UserInfo, username: &'a str) -> ListItem<'a> {
+    let name = if current.username == username {
+        current_user_span(username)
     } else {
-        ListItem::new(Span::from(current))
+        Span::from(current.username.as_str())
+    };
+
+    let mut spans = vec![status_span(current.status), name];
+    if let Some(note) = &current.note {
+        spans.push(Span::styled(
+            format!("  {note}"),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    ListItem::new(Spans::from(spans))
+}
+
+/// Chats with users who've gone offline are greyed out
+fn chat_list_item(username: &str, offline: bool, unread: usize) -> ListItem<'_> {
+    let name = if offline {
+        Span::styled(
+            format!("{username} (offline)"),
+            Style::default().fg(Color::DarkGray),
+        )
+    } else {
+        Span::from(username)
+    };
+    ListItem::new(Spans::from(vec![name, unread_span(unread)]))
+}
+
+/// Badge with the number of unread messages, nothing if there are none
+fn unread_span<'a>(unread: usize) -> Span<'a> {
+    if unread == 0 {
+        return Span::from("");
     }
+    let style = Style::default()
+        .fg(Color::Yellow)
+        .add_modifier(Modifier::BOLD);
+    Span::styled(format!(" ({unread})"), style)
 }
 
-fn current_user_span(username: &str) -> Span {
+fn room_info_list_item(room: &RoomInfo) -> ListItem<'_> {
+    let mut spans = vec![Span::from(room.name.as_str())];
+    if room.private {
+        spans.push(Span::from(" (private)"));
+    }
+    if let Some(topic) = &room.topic {
+        spans.push(Span::styled(
+            format!("  {topic}"),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    ListItem::new(Spans::from(spans))
+}
+
+/// Room users are prefixed with their status, then `~` for the owner and `@`
+/// for operators, banned users are greyed out
+fn room_user_list_item<'a>(current: &'a RoomUser, username: &'a str) -> ListItem<'a> {
+    match current {
+        RoomUser::Member(member) => {
+            let prefix = match member.role {
+                Role::Owner => "~",
+                Role::Operator => "@",
+                Role::Member => " ",
+            };
+            let name = if member.username == username {
+                current_user_span(username)
+            } else {
+                Span::from(member.username.as_str())
+            };
+            let spans = vec![status_span(member.status), Span::from(prefix), name];
+            ListItem::new(Spans::from(spans))
+        }
+        RoomUser::Banned(user) => ListItem::new(Span::styled(
+            format!(" {user} (banned)"),
+            Style::default().fg(Color::DarkGray),
+        )),
+    }
+}
+
+/// Coloured dot in front of a user showing whether they're around
+fn status_span<'a>(status: Status) -> Span<'a> {
+    let color = match status {
+        Status::Online => Color::Green,
+        Status::Away => Color::Yellow,
+        Status::Busy => Color::Red,
+    };
+    Span::styled("● ", Style::default().fg(color))
+}
+
+fn current_user_span(username: &str) -> Span<'_> {
     Span::styled(username, Style::default().add_modifier(Modifier::ITALIC))
 }
 
+fn reconnecting_span<'a>(reconnecting: bool) -> Span<'a> {
+    if reconnecting {
+        Span::styled(" (reconnecting…)", Style::default().fg(Color::Yellow))
+    } else {
+        Span::from("")
+    }
+}
+
 fn panel(pane: Pane, active: Pane) -> Block<'static> {
     Block::default()
         .title(pane.title())
@@ -1,36 +1,90 @@
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::pki_types::ServerName;
 
 use crate::{
-    commands::{Command, Response},
-    connection::Connection,
+    commands::{Command, Handshake, Password, Response, Welcome},
+    connection::{Connection, Stream},
     frame::Frame,
+    tls::TlsConnector,
     Error, Result,
 };
 
 pub struct Client {
-    connection: Connection,
+    connection: Connection<Box<dyn Stream>>,
     username: String,
 }
 
 impl Client {
     pub async fn connect(addr: impl ToSocketAddrs, username: String) -> Result<Self> {
         let stream = TcpStream::connect(addr).await?;
-        println!("Client connected to server at {}", stream.peer_addr()?);
 
-        let connection = Connection::new(stream);
+        let connection = Connection::new(Box::new(stream) as Box<dyn Stream>);
         Ok(Self {
             connection,
             username,
         })
     }
 
-    pub async fn hello(&mut self) -> Result<()> {
+    /// Connect over tls, verifying the server's certificate is valid for `domain`
+    pub async fn connect_tls(
+        addr: impl ToSocketAddrs,
+        domain: &str,
+        connector: TlsConnector,
+        username: String,
+    ) -> Result<Self> {
+        let domain = ServerName::try_from(domain.to_owned())
+            .map_err(|_| Error::InvalidServerName(domain.into()))?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(domain, stream).await?;
+
+        let connection = Connection::new(Box::new(stream) as Box<dyn Stream>);
+        Ok(Self {
+            connection,
+            username,
+        })
+    }
+
+    pub async fn hello(&mut self, password: Password) -> Result<()> {
         self.write_command(Command::Hello {
             username: self.username.clone(),
+            password,
+            handshake: Handshake::default(),
         })
         .await
     }
 
+    pub async fn register(&mut self, password: Password) -> Result<()> {
+        self.write_command(Command::Register {
+            username: self.username.clone(),
+            password,
+            handshake: Handshake::default(),
+        })
+        .await
+    }
+
+    /// Pick up the session `token` was issued for in an earlier welcome
+    pub async fn resume(&mut self, token: String) -> Result<()> {
+        self.write_command(Command::Resume {
+            username: self.username.clone(),
+            token,
+            handshake: Handshake::default(),
+        })
+        .await
+    }
+
+    /// Wait for the server to accept or reject the hello/register/resume just sent
+    pub async fn welcome(&mut self) -> Result<Welcome> {
+        loop {
+            match self.read_response().await? {
+                Response::Welcome(welcome) => return Ok(welcome),
+                Response::Err(err) => return Err(Error::Rejected(err)),
+                // Keep alives can arrive before the hello is handled
+                _ => {}
+            }
+        }
+    }
+
     pub async fn write_command(&mut self, command: Command) -> Result<()> {
         let frame = Frame::from(command);
         self.connection.write_frame(&frame).await?;
@@ -1,32 +1,194 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::Error;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Target {
     Username(String),
     Room(String),
 }
 
-pub const KEEP_ALIVE_INTERVAL: u64 = 5;
-pub const KEEP_ALIVE_CHECK: u64 = 10;
+/// Standing of a member in a room, ordered from least to most privileged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum Role {
+    Member,
+    /// Can kick, ban and unban members
+    Operator,
+    /// Created the room, can also grant and revoke operator
+    Owner,
+}
 
-#[derive(Debug)]
-pub struct KeepAlive;
+/// How available a user says they are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Member {
+    pub username: String,
+    pub role: Role,
+    #[serde(default)]
+    pub status: Status,
+}
+
+/// A logged in user as shown when listing all users
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub status: Status,
+    /// What they're up to, set along with their status
+    pub note: Option<String>,
+}
+
+/// A room as shown when listing all rooms
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    pub private: bool,
+}
+
+/// Why someone is no longer in a room
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LeaveReason {
+    Left,
+    Kicked,
+    Banned,
+    /// Stopped sending keep alives and didn't resume in time
+    TimedOut,
+    /// Connection closed and didn't resume in time
+    Disconnected,
+}
+
+pub type MessageId = u64;
+
+/// A message sent to a room or user, stamped by the server when received
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub timestamp: DateTime<Utc>,
+    pub sender: String,
+    pub message: String,
+}
+
+/// Revision of the protocol spoken by this build, bumped whenever a response
+/// changes in a way older clients can't read
+///
+/// 2: members, rooms and users are listed with their details instead of by name
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol revision this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features, only used once both sides agree on them in the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Capability {
+    /// Paging through older messages with `FetchHistory`
+    History,
+    /// Direct messages to offline users are held until they come back
+    OfflineDelivery,
+    /// Rooms say when someone joins or leaves them
+    RoomEvents,
+    /// Users are told when they're kicked from or invited to a room
+    Moderation,
+    /// Direct message partners are told when each other come and go
+    Presence,
+    /// Users set how available they are with `SetStatus`, and are shown as
+    /// away once every session says it's `Idle`
+    Statuses,
+    /// Rooms and direct message partners are told when someone's typing
+    Typing,
+    /// Where users got up to reading is kept with `MarkRead`
+    ReadMarkers,
+    /// A capability from a newer build that this one doesn't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// Every capability this build supports
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::History,
+    Capability::OfflineDelivery,
+    Capability::RoomEvents,
+    Capability::Moderation,
+    Capability::Presence,
+    Capability::Statuses,
+    Capability::Typing,
+    Capability::ReadMarkers,
+];
+
+/// Protocol details sent along with a hello so the server can check compatibility
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Handshake {
+    /// Missing from clients older than versioning, which are version 0
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+        }
+    }
+}
+
+/// Password sent when logging in, hidden when debug printed so it never ends up in logs
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Password(pub String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(***)")
+    }
+}
 
 #[derive(Debug)]
 pub struct Kill;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Command {
+    /// Log in to an existing account
     Hello {
         username: String,
+        password: Password,
+        #[serde(flatten)]
+        handshake: Handshake,
+    },
+    /// Create an account and log in to it
+    Register {
+        username: String,
+        password: Password,
+        #[serde(flatten)]
+        handshake: Handshake,
+    },
+    /// Pick up a session after the connection dropped, keeping its rooms and
+    /// getting anything sent in the meantime
+    Resume {
+        username: String,
+        token: String,
+        #[serde(flatten)]
+        handshake: Handshake,
     },
     KeepAlive,
     ListRooms,
     ListUsers,
+    /// Join a room, creating it if it doesn't exist. `key` lets users in to
+    /// private rooms they haven't been invited to
     JoinOrCreate {
         room: String,
+        #[serde(default)]
+        key: Option<String>,
     },
     Leave {
         room: String,
@@ -35,6 +197,94 @@ pub enum Command {
         target: Target,
         message: String,
     },
+    /// Grant or revoke operator for a member of a room, owner only
+    SetOperator {
+        room: String,
+        user: String,
+        operator: bool,
+    },
+    /// Remove a member from a room, operators only
+    Kick {
+        room: String,
+        user: String,
+    },
+    /// Remove a user from a room and stop them joining again, operators only
+    Ban {
+        room: String,
+        user: String,
+    },
+    Unban {
+        room: String,
+        user: String,
+    },
+    /// Hide a room from room lists so only invited users or those with the
+    /// key can join, operators only
+    SetPrivate {
+        room: String,
+        private: bool,
+        key: Option<String>,
+    },
+    /// Let a user join a private room, operators only
+    Invite {
+        room: String,
+        user: String,
+    },
+    /// Change what a room is about, operators only. An empty topic clears it
+    SetTopic {
+        room: String,
+        topic: String,
+    },
+    /// Let a room or user know we're writing a message to them, sent every
+    /// few seconds while we are
+    Typing {
+        target: Target,
+    },
+    /// Note that everything up to `message_id` in a room or private chat has
+    /// been read, so other sessions and later logins know
+    MarkRead {
+        target: Target,
+        message_id: MessageId,
+    },
+    /// Let everyone know how available we are, `note` says why
+    SetStatus {
+        status: Status,
+        #[serde(default)]
+        note: Option<String>,
+    },
+    /// Say whether this session has been left alone, a user who set themselves
+    /// online is shown as away while all of their sessions are
+    Idle {
+        idle: bool,
+    },
+    /// Request up to `limit` messages of a room or private chat sent before
+    /// the message with id `before`, or the most recent if none
+    FetchHistory {
+        target: Target,
+        before: Option<MessageId>,
+        limit: usize,
+    },
+}
+
+impl Command {
+    /// Whether this logs a connection in
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            Command::Hello { .. } | Command::Register { .. } | Command::Resume { .. }
+        )
+    }
+
+    /// Capability that has to be agreed on before this can be sent, none if
+    /// every client can
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Command::FetchHistory { .. } => Some(Capability::History),
+            Command::SetStatus { .. } | Command::Idle { .. } => Some(Capability::Statuses),
+            Command::Typing { .. } => Some(Capability::Typing),
+            Command::MarkRead { .. } => Some(Capability::ReadMarkers),
+            _ => None,
+        }
+    }
 }
 
 impl From<Command> for String {
@@ -51,35 +301,192 @@ impl<'a> TryFrom<&'a str> for Command {
     }
 }
 
+/// Session details sent once a hello is accepted
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Welcome {
+    /// Protocol version both sides agreed on
+    pub version: u32,
+    /// Capabilities both sides agreed on
+    pub capabilities: Vec<Capability>,
+    pub username: String,
+    pub server_name: String,
+    pub motd: Option<String>,
+    /// Seconds between keep alives, both sides send one this often
+    pub keep_alive_interval: u64,
+    /// Seconds without a keep alive before the other side is considered gone
+    pub keep_alive_timeout: u64,
+    /// Rooms the user is already a member of
+    pub rooms: Vec<String>,
+    /// Secret to pick this session back up with if the connection drops
+    pub resume_token: String,
+    /// Seconds the session is kept for after the connection drops
+    pub resume_grace: u64,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Response {
-    ListMembers { room: String, users: Vec<String> },
-    ListRooms { rooms: Vec<String> },
-    ListUsers { users: Vec<String> },
-    TellRoom { 
+    Welcome(Welcome),
+    ListMembers {
+        room: String,
+        topic: Option<String>,
+        private: bool,
+        users: Vec<Member>,
+        banned: Vec<String>,
+    },
+    ListRooms { rooms: Vec<RoomInfo> },
+    ListUsers { users: Vec<UserInfo> },
+    TellRoom {
         room: String,
-        sender: String,
-        message: String
+        message: ChatMessage,
     },
     TellUser {
         username: String,
-        sender: String,
-        message: String
+        message: ChatMessage,
+    },
+    History {
+        target: Target,
+        messages: Vec<ChatMessage>,
+    },
+    /// Sent to the rest of a room when someone joins it
+    UserJoined {
+        room: String,
+        username: String,
+    },
+    /// Sent to the rest of a room when someone leaves it
+    UserLeft {
+        room: String,
+        username: String,
+        reason: LeaveReason,
+    },
+    /// Sent to a user removed from a room by an operator
+    Kicked {
+        room: String,
+        by: String,
+        banned: bool,
+    },
+    /// Sent to the sender of direct messages that are waiting for `username`
+    /// to come online
+    Queued {
+        username: String,
+        ids: Vec<MessageId>,
+    },
+    /// Sent once queued direct messages have reached `username`
+    Delivered {
+        username: String,
+        ids: Vec<MessageId>,
+    },
+    /// Sent to users who've had direct messages with `username` when they
+    /// come online or go offline
+    Presence {
+        username: String,
+        online: bool,
+    },
+    /// Sent when `username` is writing a message, `target` is the room or
+    /// private chat it's going to as the receiver sees it
+    Typing {
+        target: Target,
+        username: String,
+    },
+    /// Sent to each of a user's sessions when they've read up to
+    /// `message_id`, and for every room and chat when they log in
+    MarkedRead {
+        target: Target,
+        message_id: MessageId,
+    },
+    /// Sent to everyone when a user's status changes, whether they set it or
+    /// went idle or came back
+    StatusChanged {
+        username: String,
+        status: Status,
+        note: Option<String>,
+    },
+    /// Sent to a user invited to a private room
+    Invited {
+        room: String,
+        by: String,
     },
     KeepAlive,
     Err(ResponseError),
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, thiserror::Error, Deserialize, Serialize)]
 pub enum ResponseError {
-    UserAlreadyExists(String),
+    #[error("an account named `{0}` already exists")]
+    AccountAlreadyExists(String),
+    #[error("invalid username or password")]
+    InvalidCredentials,
+    /// The client's protocol version is outside the range the server supports
+    #[error("protocol version {version} is not supported, server accepts {min} to {max}")]
+    IncompatibleVersion {
+        version: u32,
+        min: u32,
+        max: u32,
+    },
+    #[error("session can't be resumed, log in again")]
+    InvalidResumeToken,
+    #[error("log in before sending anything else")]
+    NotAuthenticated,
+    #[error("already logged in on this connection")]
+    AlreadyAuthenticated,
+    /// The command needs a capability that wasn't agreed on in the handshake
+    #[error("`{0:?}` wasn't agreed on when logging in")]
+    NotNegotiated(Capability),
+    /// The client sent something that isn't a command
+    #[error("invalid command `{0}`")]
+    InvalidCommand(String),
+    #[error("frame bigger than {0} bytes")]
+    FrameTooBig(usize),
+    #[error("no user named `{0}`")]
+    UnknownUser(String),
+    /// Sent instead of holding on to a direct message when offline delivery
+    /// wasn't agreed on
+    #[error("`{0}` is offline")]
+    UserOffline(String),
+    #[error("room `{0}` does not exist")]
     RoomDoesNotExist(String),
+    #[error("only the owner of `{0}` can do that")]
+    NotRoomOwner(String),
+    #[error("only operators of `{0}` can do that")]
+    NotRoomOperator(String),
+    #[error("`{user}` can't be moderated in `{room}`")]
+    CannotModerate {
+        user: String,
+        room: String,
+    },
+    #[error("`{0}` is private, an invite or key is needed to join")]
+    Forbidden(String),
+    #[error("banned from `{0}`")]
+    BannedFromRoom(String),
+    /// Sent before disconnecting a client that fell too far behind reading
+    #[error("disconnected for falling too far behind")]
+    TooSlow,
+    #[error("user `{user}` is not in room `{room}`")]
     UserNotInRoom {
         user: String,
         room: String
     }
 }
 
+impl Response {
+    /// Capability a client has to have agreed on to be sent this, none if
+    /// every client understands it
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Response::History { .. } => Some(Capability::History),
+            Response::Queued { .. } | Response::Delivered { .. } => {
+                Some(Capability::OfflineDelivery)
+            }
+            Response::UserJoined { .. } | Response::UserLeft { .. } => Some(Capability::RoomEvents),
+            Response::Kicked { .. } | Response::Invited { .. } => Some(Capability::Moderation),
+            Response::Presence { .. } => Some(Capability::Presence),
+            Response::StatusChanged { .. } => Some(Capability::Statuses),
+            Response::Typing { .. } => Some(Capability::Typing),
+            Response::MarkedRead { .. } => Some(Capability::ReadMarkers),
+            _ => None,
+        }
+    }
+}
+
 impl From<Response> for String {
     fn from(res: Response) -> Self {
         serde_json::to_string(&res).unwrap()
@@ -93,3 +500,33 @@ impl<'a> TryFrom<&'a str> for Response {
         serde_json::from_str(value).map_err(|_| Error::InvalidResponse(value.into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_capabilities_still_parse() {
+        let raw = r#"{"Hello":{"username":"alice","password":"pw","version":2,"capabilities":["History","Teleport"]}}"#;
+        let command = Command::try_from(raw).unwrap();
+
+        let Command::Hello { handshake, .. } = command else {
+            panic!("expected hello, got {command:?}");
+        };
+        assert_eq!(handshake.version, 2);
+        assert_eq!(
+            handshake.capabilities,
+            [Capability::History, Capability::Unknown]
+        );
+    }
+
+    #[test]
+    fn hello_without_version() {
+        let raw = r#"{"Hello":{"username":"alice","password":"pw"}}"#;
+        let Command::Hello { handshake, .. } = Command::try_from(raw).unwrap() else {
+            panic!("expected hello");
+        };
+        assert_eq!(handshake.version, 0);
+        assert!(handshake.capabilities.is_empty());
+    }
+}
@@ -1,10 +1,7 @@
 use std::io::{self, Cursor};
 
 use bytes::{Buf, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, BufWriter},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufWriter};
 
 use crate::{
     frame::{Frame, FrameError},
@@ -13,14 +10,19 @@ use crate::{
 
 const READ_BUFFER_CAPACITY: usize = 16 * 1024;
 
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+/// Any byte stream frames can be sent over, such as a plain tcp or tls stream
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+pub struct Connection<S> {
+    stream: BufWriter<S>,
     // Buffer for reading frames
     buffer: BytesMut,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: Stream> Connection<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             stream: BufWriter::new(stream),
             buffer: BytesMut::with_capacity(READ_BUFFER_CAPACITY),
@@ -56,7 +58,8 @@ impl Connection {
 
         match Frame::parse(&mut buf) {
             Ok(frame) => {
-                self.buffer.advance(4 + frame.raw().len());
+                let len = buf.position() as usize;
+                self.buffer.advance(len);
                 Ok(Some(frame))
             }
             Err(FrameError::Incomplete) => Ok(None),
@@ -3,6 +3,9 @@ use std::io::{self, Cursor, Read};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 
+/// Largest payload a single frame may carry
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Frame {
     raw: String,
@@ -19,7 +22,7 @@ pub enum FrameError {
 impl Frame {
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Self, FrameError> {
         let len = read_u32(src)?;
-        if len > 16 * 1024 {
+        if len as usize > MAX_FRAME_SIZE {
             return Err(FrameError::TooBig);
         }
 
@@ -27,7 +30,8 @@ impl Frame {
         src.read_exact(&mut out)
             .map_err(|_| FrameError::Incomplete)?;
 
-        let str = String::from_utf8(out).unwrap();
+        // Invalid text is left for whatever parses the frame to reject
+        let str = String::from_utf8_lossy(&out).into_owned();
         Ok(Self { raw: str })
     }
 
@@ -97,4 +101,16 @@ mod tests {
         let parsed = Frame::parse(&mut cursor).unwrap();
         assert_eq!(frame, parsed);
     }
+
+    #[tokio::test]
+    async fn invalid_utf8() {
+        let mut out = Vec::new();
+        out.write_u32(2).await.unwrap();
+        out.extend_from_slice(&[0xff, b'a']);
+
+        let mut cursor = Cursor::new(&out[..]);
+        let parsed = Frame::parse(&mut cursor).unwrap();
+        assert_eq!(parsed.raw(), "\u{fffd}a");
+        assert_eq!(cursor.position(), 6);
+    }
 }
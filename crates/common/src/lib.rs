@@ -6,6 +6,7 @@ pub mod client;
 pub mod commands;
 pub mod connection;
 pub mod frame;
+pub mod tls;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -23,6 +24,12 @@ pub enum Error {
     CommandError(String),
     #[error("received a command with an unexpected type")]
     BadCommandType,
+    #[error("tls error occured {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[error("invalid tls server name `{0}`")]
+    InvalidServerName(String),
+    #[error("server rejected hello: {0}")]
+    Rejected(commands::ResponseError),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,91 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::Result;
+
+/// Build an acceptor for the server from a pem encoded certificate chain and private key
+pub fn acceptor(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<TlsAcceptor> {
+    let certs = load_certs(cert.as_ref())?;
+    let key = PrivateKeyDer::from_pem_file(key.as_ref()).map_err(invalid_data)?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a connector for the client trusting the certificates in `ca`,
+/// or the bundled web pki roots if none given
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(invalid_data)?
+        .collect::<core::result::Result<_, _>>()
+        .map_err(invalid_data)?;
+
+    Ok(certs)
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    use super::*;
+    use crate::{connection::Connection, frame::Frame};
+
+    #[tokio::test]
+    async fn round_trip_self_signed() {
+        let dir = std::env::temp_dir().join(format!("rs_chat_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        let acceptor = acceptor(&cert, &key).unwrap();
+        let connector = connector(Some(&cert)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let domain = ServerName::try_from("localhost").unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(domain, client),
+            acceptor.accept(server)
+        );
+
+        let mut client = Connection::new(client.unwrap());
+        let mut server = Connection::new(server.unwrap());
+
+        let frame = Frame::from("over tls");
+        client.write_frame(&frame).await.unwrap();
+        assert_eq!(server.read_frame().await.unwrap(), Some(frame));
+    }
+}
@@ -0,0 +1,172 @@
+//! Simulates hundreds of clients chatting in rooms at once and reports how
+//! many messages the server delivers per second, and how long logging in and
+//! sending a direct message take while it's busy.
+//!
+//! Run with `cargo bench -p server`
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::{
+    client::Client,
+    commands::{Command, Password, Response, Target},
+};
+use server::{config::Config, server::Server};
+use tokio::sync::Barrier;
+
+/// Messages each client sends to its room
+const MESSAGES: usize = 20;
+
+struct Scenario {
+    name: &'static str,
+    clients: usize,
+    /// Clients in each room, the rest are spread over the others
+    rooms: Vec<usize>,
+}
+
+#[tokio::main]
+async fn main() {
+    let scenarios = [
+        Scenario {
+            name: "400 clients in 20 rooms",
+            clients: 400,
+            rooms: vec![20; 20],
+        },
+        Scenario {
+            name: "400 clients, 200 in one busy room",
+            clients: 400,
+            rooms: std::iter::once(200).chain(vec![20; 10]).collect(),
+        },
+    ];
+
+    for scenario in scenarios {
+        run(scenario).await;
+    }
+}
+
+async fn run(scenario: Scenario) {
+    assert_eq!(scenario.rooms.iter().sum::<usize>(), scenario.clients);
+
+    let config = Config {
+        outbound_queue: usize::MAX,
+        keep_alive_timeout: Duration::from_secs(600),
+        ..Config::default()
+    };
+    let server = Arc::new(Server::bind("127.0.0.1:0", config).await.unwrap());
+    let addr = server.local_addr().unwrap();
+    let listening = server.clone();
+    tokio::spawn(async move { listening.listen().await });
+
+    // Everyone joins first so no one misses messages, then they all start at once
+    let barrier = Arc::new(Barrier::new(scenario.clients + 1));
+    let mut tasks = Vec::new();
+    for (room, &members) in scenario.rooms.iter().enumerate() {
+        for member in 0..members {
+            let name = format!("user{room}_{member}");
+            let barrier = barrier.clone();
+            tasks.push(tokio::spawn(chat(
+                addr,
+                name,
+                format!("room{room}"),
+                members,
+                barrier,
+            )));
+        }
+    }
+
+    let mut probe = login(addr, "probe").await;
+    let mut partner = login(addr, "partner").await;
+
+    barrier.wait().await;
+    let start = Instant::now();
+
+    // Probe how responsive everything else is while the rooms are busy
+    let login_start = Instant::now();
+    login(addr, "latecomer").await;
+    let login_time = login_start.elapsed();
+
+    let dm_start = Instant::now();
+    probe
+        .write_command(Command::Send {
+            target: Target::Username("partner".into()),
+            message: "ping".into(),
+        })
+        .await
+        .unwrap();
+    loop {
+        if let Response::TellUser { .. } = partner.read_response().await.unwrap() {
+            break;
+        }
+    }
+    let dm_time = dm_start.elapsed();
+
+    let mut delivered = 0;
+    for task in tasks {
+        delivered += task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    println!("{}:", scenario.name);
+    println!(
+        "  delivered {delivered} messages in {elapsed:.2?}, {:.0} messages/s",
+        delivered as f64 / elapsed.as_secs_f64()
+    );
+    println!("  login took {login_time:.2?}, direct message took {dm_time:.2?}");
+}
+
+async fn login(addr: SocketAddr, name: &str) -> Client {
+    let mut client = Client::connect(addr, name.into()).await.unwrap();
+    client.register(Password("password".into())).await.unwrap();
+    client.welcome().await.unwrap();
+    client
+}
+
+/// Join `room`, send to it and read until every member's messages arrived,
+/// returning how many were received
+async fn chat(
+    addr: SocketAddr,
+    name: String,
+    room: String,
+    members: usize,
+    barrier: Arc<Barrier>,
+) -> usize {
+    let mut client = login(addr, &name).await;
+    client
+        .write_command(Command::JoinOrCreate {
+            room: room.clone(),
+            key: None,
+        })
+        .await
+        .unwrap();
+    loop {
+        if let Response::ListMembers { .. } = client.read_response().await.unwrap() {
+            break;
+        }
+    }
+
+    barrier.wait().await;
+
+    for i in 0..MESSAGES {
+        client
+            .write_command(Command::Send {
+                target: Target::Room(room.clone()),
+                message: format!("message {i} from {name}"),
+            })
+            .await
+            .unwrap();
+    }
+
+    let expected = members * MESSAGES;
+    let mut received = 0;
+    while received < expected {
+        match client.read_response().await.unwrap() {
+            Response::TellRoom { .. } => received += 1,
+            Response::Err(err) => panic!("{name}: {err}"),
+            _ => {}
+        }
+    }
+    received
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use common::commands::ResponseError;
+
+use crate::json_file;
+
+/// Registered users and their salted password hashes
+///
+/// If backed by a file the whole store is rewritten as json whenever
+/// a user registers
+#[derive(Debug, Default)]
+pub struct Accounts {
+    path: Option<PathBuf>,
+    users: HashMap<String, String>,
+}
+
+impl Accounts {
+    /// Load the store at `path`, starting empty if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            path: Some(path.into()),
+            users: json_file::load(path)?,
+        })
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    pub fn password_hash(&self, username: &str) -> Option<String> {
+        self.users.get(username).cloned()
+    }
+
+    pub fn register(&mut self, username: String, hash: String) -> Result<(), ResponseError> {
+        if self.contains(&username) {
+            return Err(ResponseError::AccountAlreadyExists(username));
+        }
+
+        self.users.insert(username, hash);
+        if let Err(err) = self.save() {
+            tracing::error!(%err, "failed to save accounts");
+        }
+
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            json_file::save(path, &self.users)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash `password` with a fresh random salt, encoded as a PHC string
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Hash to check passwords against for users that don't exist, so turning
+/// them away takes as long as a wrong password and doesn't give away who does
+pub fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("not a real password"))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_hashed_password() {
+        let hash = hash_password("hunter2");
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+    }
+
+    #[test]
+    fn dummy_hash_takes_no_password() {
+        assert!(!verify_password("", dummy_hash()));
+        assert!(!verify_password("hunter2", dummy_hash()));
+    }
+
+    #[test]
+    fn salts_differ() {
+        assert_ne!(hash_password("hunter2"), hash_password("hunter2"));
+    }
+
+    #[test]
+    fn register_twice() {
+        let mut accounts = Accounts::default();
+        accounts.register("alice".into(), "hash".into()).unwrap();
+
+        let err = accounts.register("alice".into(), "other".into());
+        assert!(matches!(err, Err(ResponseError::AccountAlreadyExists(_))));
+        assert_eq!(accounts.password_hash("alice").as_deref(), Some("hash"));
+    }
+}
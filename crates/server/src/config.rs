@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+/// What to do when a client isn't reading responses as fast as they're sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SlowConsumer {
+    /// Throw away the oldest queued response to make room
+    DropOldest,
+    /// Drop the connection, the client can resume its session
+    Disconnect,
+}
+
+/// Settings for a running server
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Name sent to clients when they connect
+    pub name: String,
+    /// Message of the day sent to clients when they connect
+    pub motd: Option<String>,
+    /// Address to accept websocket connections on, disabled if none
+    pub ws_address: Option<SocketAddr>,
+    /// File chat messages are appended to, kept in memory only if none
+    pub history_path: Option<PathBuf>,
+    /// File registered accounts are stored in, kept in memory only if none
+    pub accounts_path: Option<PathBuf>,
+    /// File direct messages waiting for offline users are stored in, kept in
+    /// memory only if none
+    pub mailbox_path: Option<PathBuf>,
+    /// Pem encoded certificate chain to serve tls with, plain tcp if none
+    pub tls_cert: Option<PathBuf>,
+    /// Pem encoded private key for `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Number of messages replayed to a user when joining a room or saying hello
+    pub backlog: usize,
+    /// How long a user's rooms and missed messages are kept after their
+    /// connection drops, so they can resume
+    pub resume_grace: Duration,
+    /// How often a keep alive is sent to each connection, clients are told
+    /// to send theirs this often too
+    pub keep_alive_interval: Duration,
+    /// How long a connection can go without sending anything before it's
+    /// dropped
+    pub keep_alive_timeout: Duration,
+    /// Most responses queued for a connection, or held for a session waiting
+    /// to resume, before `slow_consumer` applies
+    pub outbound_queue: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: String::from("rs_chat"),
+            motd: None,
+            ws_address: None,
+            history_path: None,
+            accounts_path: None,
+            mailbox_path: None,
+            tls_cert: None,
+            tls_key: None,
+            backlog: 50,
+            resume_grace: Duration::from_secs(60),
+            keep_alive_interval: Duration::from_secs(5),
+            keep_alive_timeout: Duration::from_secs(15),
+            outbound_queue: 1024,
+            slow_consumer: SlowConsumer::Disconnect,
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+use common::commands::{ChatMessage, MessageId, Response, Target};
+use serde::{Deserialize, Serialize};
+
+/// A single chat message as written to the history log
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    pub target: Target,
+    #[serde(flatten)]
+    pub message: ChatMessage,
+}
+
+impl Entry {
+    pub fn conversation(&self) -> Conversation {
+        match &self.target {
+            Target::Room(room) => Conversation::Room(room.clone()),
+            Target::Username(user) => Conversation::direct(&self.message.sender, user),
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        match &self.target {
+            Target::Room(room) => Response::TellRoom {
+                room: room.clone(),
+                message: self.message.clone(),
+            },
+            Target::Username(username) => Response::TellUser {
+                username: username.clone(),
+                message: self.message.clone(),
+            },
+        }
+    }
+}
+
+/// Key messages are grouped under, direct messages between two users
+/// share a conversation no matter who sent them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Room(String),
+    Direct(String, String),
+}
+
+impl Conversation {
+    pub fn direct(a: &str, b: &str) -> Self {
+        if a <= b {
+            Self::Direct(a.into(), b.into())
+        } else {
+            Self::Direct(b.into(), a.into())
+        }
+    }
+
+    fn includes(&self, user: &str) -> bool {
+        match self {
+            Conversation::Room(_) => false,
+            Conversation::Direct(a, b) => a == user || b == user,
+        }
+    }
+}
+
+/// Append only store of every message sent through the server
+///
+/// All messages are kept in memory, and if backed by a file each new
+/// message is written as a line of json so history survives restarts
+#[derive(Debug, Default)]
+pub struct History {
+    log: Option<File>,
+    conversations: HashMap<Conversation, Vec<Entry>>,
+    /// Everyone each user has had direct messages with
+    partners: HashMap<String, BTreeSet<String>>,
+    next_id: MessageId,
+}
+
+impl History {
+    /// Open the log at `path`, creating it if needed, and load any existing messages
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut history = Self::default();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let line = line?;
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => {
+                        history.insert(entry);
+                    }
+                    Err(err) => tracing::warn!(%err, "skipping malformed history entry"),
+                }
+            }
+        }
+
+        history.log = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(history)
+    }
+
+    /// Stamp a new message with an id and the current time and store it
+    pub fn record(&mut self, target: Target, sender: String, message: String) -> &Entry {
+        let entry = Entry {
+            target,
+            message: ChatMessage {
+                id: self.next_id,
+                timestamp: Utc::now(),
+                sender,
+                message,
+            },
+        };
+
+        if let Some(log) = &mut self.log {
+            let line = serde_json::to_string(&entry).unwrap();
+            if let Err(err) = writeln!(log, "{line}") {
+                tracing::error!(%err, "failed to write history");
+            }
+        }
+
+        self.insert(entry)
+    }
+
+    /// Last `n` messages of a conversation, oldest first
+    pub fn recent(&self, conversation: &Conversation, n: usize) -> &[Entry] {
+        match self.conversations.get(conversation) {
+            Some(entries) => &entries[entries.len().saturating_sub(n)..],
+            None => &[],
+        }
+    }
+
+    /// Up to `limit` messages sent before the message with id `before`, oldest first
+    pub fn before(&self, conversation: &Conversation, before: MessageId, limit: usize) -> &[Entry] {
+        match self.conversations.get(conversation) {
+            Some(entries) => {
+                // Ids are handed out in order so each conversation is sorted by id
+                let end = entries.partition_point(|e| e.message.id < before);
+                &entries[end.saturating_sub(limit)..end]
+            }
+            None => &[],
+        }
+    }
+
+    /// Every direct message conversation `user` is a part of
+    pub fn direct_conversations<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> impl Iterator<Item = &'a Conversation> + 'a {
+        self.conversations.keys().filter(move |c| c.includes(user))
+    }
+
+    /// Everyone `user` has had direct messages with
+    pub fn partners<'a>(&'a self, user: &str) -> impl Iterator<Item = &'a String> + 'a {
+        self.partners.get(user).into_iter().flatten()
+    }
+
+    pub fn are_partners(&self, a: &str, b: &str) -> bool {
+        self.partners.get(a).is_some_and(|p| p.contains(b))
+    }
+
+    fn insert(&mut self, entry: Entry) -> &Entry {
+        self.next_id = self.next_id.max(entry.message.id + 1);
+
+        if let Target::Username(recipient) = &entry.target {
+            let sender = &entry.message.sender;
+            for (a, b) in [(sender, recipient), (recipient, sender)] {
+                self.partners
+                    .entry(a.clone())
+                    .or_default()
+                    .insert(b.clone());
+            }
+        }
+
+        let entries = self.conversations.entry(entry.conversation()).or_default();
+        entries.push(entry);
+        entries.last().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(history: &mut History, message: &str) -> MessageId {
+        let target = Target::Room("room".into());
+        history
+            .record(target, "alice".into(), message.into())
+            .message
+            .id
+    }
+
+    fn messages(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.message.message.as_str()).collect()
+    }
+
+    #[test]
+    fn direct_messages_share_conversation() {
+        let mut history = History::default();
+        history.record(
+            Target::Username("bob".into()),
+            "alice".into(),
+            "hi bob".into(),
+        );
+        history.record(
+            Target::Username("alice".into()),
+            "bob".into(),
+            "hi alice".into(),
+        );
+
+        let conversation = Conversation::direct("bob", "alice");
+        assert_eq!(history.recent(&conversation, 10).len(), 2);
+        assert_eq!(history.direct_conversations("alice").count(), 1);
+        assert!(history.are_partners("bob", "alice"));
+        assert_eq!(history.partners("alice").collect::<Vec<_>>(), ["bob"]);
+    }
+
+    #[test]
+    fn recent_returns_newest() {
+        let mut history = History::default();
+        for i in 0..5 {
+            room(&mut history, &i.to_string());
+        }
+
+        let recent = history.recent(&Conversation::Room("room".into()), 2);
+        assert_eq!(messages(recent), ["3", "4"]);
+    }
+
+    #[test]
+    fn before_pages_backwards() {
+        let mut history = History::default();
+        for i in 0..5 {
+            room(&mut history, &i.to_string());
+        }
+
+        let conversation = Conversation::Room("room".into());
+        assert_eq!(messages(history.before(&conversation, 3, 2)), ["1", "2"]);
+        assert_eq!(messages(history.before(&conversation, 1, 2)), ["0"]);
+        assert!(history.before(&conversation, 0, 2).is_empty());
+    }
+
+    #[test]
+    fn reload_from_log() {
+        let path = std::env::temp_dir().join(format!("rs_chat_history_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut history = History::open(&path).unwrap();
+        room(&mut history, "persisted");
+        drop(history);
+
+        // Ids keep increasing from where the log left off
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(room(&mut history, "new"), 1);
+
+        let recent = history.recent(&Conversation::Room("room".into()), 10);
+        assert_eq!(messages(recent), ["persisted", "new"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Read the json at `path`, or the default if it doesn't exist yet
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    if path.exists() {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    } else {
+        Ok(T::default())
+    }
+}
+
+/// Replace `path` with `value` as json. It's written next to it first and
+/// renamed over it, so a crash or full disk part way through leaves the old
+/// file rather than a truncated one
+pub fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    file.write_all(&serde_json::to_vec(value)?)?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn save_replaces_whole_file() {
+        let path = std::env::temp_dir().join(format!("rs_chat_json_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(load::<HashMap<String, u32>>(&path).unwrap().is_empty());
+
+        let long = HashMap::from([("alice".to_owned(), 1), ("bob".to_owned(), 2)]);
+        save(&path, &long).unwrap();
+        let short = HashMap::from([("carol".to_owned(), 3)]);
+        save(&path, &short).unwrap();
+
+        assert_eq!(load::<HashMap<String, u32>>(&path).unwrap(), short);
+        assert!(!temp_path(&path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
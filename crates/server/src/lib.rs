@@ -1,2 +1,13 @@
+pub mod accounts;
+pub mod config;
+pub mod history;
+pub mod json_file;
+pub mod mailbox;
+pub mod markers;
+pub mod memberships;
+pub mod outbox;
+pub mod permissions;
+pub mod room;
 pub mod server;
 pub mod state;
+pub mod websocket;
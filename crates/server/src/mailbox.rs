@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use common::commands::MessageId;
+
+use crate::{history::Entry, json_file};
+
+/// Direct messages sent to users while they were offline, waiting to be
+/// delivered when they next say hello
+///
+/// If backed by a file the whole mailbox is rewritten as json whenever
+/// something is queued or delivered, so nothing waiting is lost on restart
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    path: Option<PathBuf>,
+    queued: HashMap<String, Vec<Entry>>,
+}
+
+impl Mailbox {
+    /// Load the mailbox at `path`, starting empty if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            path: Some(path.into()),
+            queued: json_file::load(path)?,
+        })
+    }
+
+    pub fn push(&mut self, recipient: String, entry: Entry) {
+        self.queued.entry(recipient).or_default().push(entry);
+        self.save();
+    }
+
+    /// Remove and return everything queued for `recipient`, oldest first
+    pub fn take(&mut self, recipient: &str) -> Vec<Entry> {
+        let entries = self.queued.remove(recipient).unwrap_or_default();
+        if !entries.is_empty() {
+            self.save();
+        }
+        entries
+    }
+
+    /// Ids of messages `sender` sent that are still waiting, grouped by recipient
+    pub fn sent_by(&self, sender: &str) -> HashMap<&str, Vec<MessageId>> {
+        let mut sent = HashMap::new();
+        for (recipient, entries) in &self.queued {
+            let ids: Vec<_> = entries
+                .iter()
+                .filter(|e| e.message.sender == sender)
+                .map(|e| e.message.id)
+                .collect();
+            if !ids.is_empty() {
+                sent.insert(recipient.as_str(), ids);
+            }
+        }
+        sent
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Err(err) = json_file::save(path, &self.queued) {
+            tracing::error!(%err, "failed to save mailbox");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use common::commands::{ChatMessage, Target};
+
+    use super::*;
+
+    fn entry(id: MessageId, sender: &str, recipient: &str) -> Entry {
+        Entry {
+            target: Target::Username(recipient.into()),
+            message: ChatMessage {
+                id,
+                timestamp: Utc::now(),
+                sender: sender.into(),
+                message: "hi".into(),
+            },
+        }
+    }
+
+    #[test]
+    fn take_empties_queue() {
+        let mut mailbox = Mailbox::default();
+        mailbox.push("bob".into(), entry(0, "alice", "bob"));
+        mailbox.push("bob".into(), entry(1, "carol", "bob"));
+        mailbox.push("dave".into(), entry(2, "alice", "dave"));
+
+        let sent = mailbox.sent_by("alice");
+        assert_eq!(sent["bob"], [0]);
+        assert_eq!(sent["dave"], [2]);
+
+        assert_eq!(mailbox.take("bob").len(), 2);
+        assert!(mailbox.take("bob").is_empty());
+        assert!(!mailbox.sent_by("alice").contains_key("bob"));
+    }
+
+    #[test]
+    fn reload_from_file() {
+        let path = std::env::temp_dir().join(format!("rs_chat_mailbox_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut mailbox = Mailbox::open(&path).unwrap();
+        mailbox.push("bob".into(), entry(0, "alice", "bob"));
+        mailbox.push("carol".into(), entry(1, "alice", "carol"));
+        mailbox.take("carol");
+        drop(mailbox);
+
+        let mut mailbox = Mailbox::open(&path).unwrap();
+        assert_eq!(mailbox.sent_by("alice").len(), 1);
+        let delivered: Vec<_> = mailbox.take("bob").iter().map(|e| e.message.id).collect();
+        assert_eq!(delivered, [0]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
-use std::{error::Error, net::SocketAddr};
+use std::{error::Error, net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::Parser;
-use server::server::Server;
+use server::{
+    config::{Config, SlowConsumer},
+    server::Server,
+};
 use tracing::Level;
 
 #[derive(Debug, Parser)]
@@ -11,6 +14,48 @@ struct Args {
     #[arg(short, default_value = "127.0.0.1:4000")]
     /// The address to accept connections on
     address: SocketAddr,
+    #[arg(long, default_value = "rs_chat")]
+    /// Name of the server shown to clients
+    name: String,
+    #[arg(long)]
+    /// Message of the day shown to clients
+    motd: Option<String>,
+    #[arg(long)]
+    /// The address to accept websocket connections on
+    ws_address: Option<SocketAddr>,
+    #[arg(long, default_value = "history.jsonl")]
+    /// File to persist chat history to
+    history: PathBuf,
+    #[arg(long, default_value = "accounts.json")]
+    /// File to store registered accounts in
+    accounts: PathBuf,
+    #[arg(long, default_value = "mailbox.json")]
+    /// File to store direct messages waiting for offline users in
+    mailbox: PathBuf,
+    #[arg(long, requires = "tls_key")]
+    /// Pem encoded certificate chain to accept tls connections with
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    /// Pem encoded private key for the tls certificate
+    tls_key: Option<PathBuf>,
+    #[arg(long, default_value_t = 50)]
+    /// Number of messages replayed when joining a room
+    backlog: usize,
+    #[arg(long, default_value_t = 60)]
+    /// Seconds a dropped connection's session is kept for it to resume
+    resume_grace: u64,
+    #[arg(long, default_value_t = 5)]
+    /// Seconds between keep alives sent to and expected from clients
+    keep_alive_interval: u64,
+    #[arg(long, default_value_t = 15)]
+    /// Seconds a client can go without sending anything before it's dropped
+    keep_alive_timeout: u64,
+    #[arg(long, default_value_t = 1024)]
+    /// Most responses queued for a client before it counts as too slow
+    outbound_queue: usize,
+    #[arg(long, value_enum, default_value_t = SlowConsumer::Disconnect)]
+    /// What to do with a client that's too slow
+    slow_consumer: SlowConsumer,
 }
 
 #[tokio::main]
@@ -23,7 +68,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let server = Server::bind(args.address).await?;
+    let config = Config {
+        name: args.name,
+        motd: args.motd,
+        ws_address: args.ws_address,
+        history_path: Some(args.history),
+        accounts_path: Some(args.accounts),
+        mailbox_path: Some(args.mailbox),
+        tls_cert: args.tls_cert,
+        tls_key: args.tls_key,
+        backlog: args.backlog,
+        resume_grace: Duration::from_secs(args.resume_grace),
+        keep_alive_interval: Duration::from_secs(args.keep_alive_interval),
+        keep_alive_timeout: Duration::from_secs(args.keep_alive_timeout),
+        outbound_queue: args.outbound_queue,
+        slow_consumer: args.slow_consumer,
+    };
+    let server = Server::bind(args.address, config).await?;
 
     tokio::select! {
         _ = server.listen() => {},
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use common::commands::{MessageId, Target};
+
+/// Last message each user has read in each of their rooms and private chats,
+/// shared by all their sessions
+#[derive(Debug, Default)]
+pub struct ReadMarkers {
+    markers: HashMap<String, HashMap<Target, MessageId>>,
+}
+
+impl ReadMarkers {
+    /// Move `user`'s marker for `target` up to `id`, returns false if they'd
+    /// already read that far
+    pub fn mark(&mut self, user: &str, target: Target, id: MessageId) -> bool {
+        let markers = self.markers.entry(user.into()).or_default();
+        match markers.get(&target) {
+            Some(read) if *read >= id => false,
+            _ => {
+                markers.insert(target, id);
+                true
+            }
+        }
+    }
+
+    /// Every marker `user` has, with where it's for
+    pub fn markers<'a>(&'a self, user: &str) -> impl Iterator<Item = (&'a Target, MessageId)> {
+        let markers = self.markers.get(user).into_iter().flatten();
+        markers.map(|(target, id)| (target, *id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moves_forward() {
+        let mut markers = ReadMarkers::default();
+        let room = Target::Room("room".into());
+
+        assert!(markers.mark("alice", room.clone(), 5));
+        assert!(!markers.mark("alice", room.clone(), 3));
+        assert!(!markers.mark("alice", room.clone(), 5));
+        assert!(markers.mark("alice", room.clone(), 8));
+
+        let all: Vec<_> = markers.markers("alice").collect();
+        assert_eq!(all, [(&room, 8)]);
+        assert_eq!(markers.markers("bob").count(), 0);
+    }
+}
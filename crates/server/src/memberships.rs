@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Which rooms each user is in and has invites waiting for, kept alongside
+/// the rooms so a user's can be found without locking every room
+#[derive(Debug, Default)]
+pub struct Memberships {
+    rooms: HashMap<String, BTreeSet<String>>,
+    /// Rooms each user was invited to and who invited them
+    invites: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl Memberships {
+    /// Joining uses up any invite to the room
+    pub fn join(&mut self, user: &str, room: &str) {
+        self.rooms
+            .entry(user.into())
+            .or_default()
+            .insert(room.into());
+        self.uninvite(user, room);
+    }
+
+    pub fn leave(&mut self, user: &str, room: &str) {
+        if let Some(rooms) = self.rooms.get_mut(user) {
+            rooms.remove(room);
+            if rooms.is_empty() {
+                self.rooms.remove(user);
+            }
+        }
+    }
+
+    pub fn invite(&mut self, user: &str, room: &str, by: &str) {
+        self.invites
+            .entry(user.into())
+            .or_default()
+            .insert(room.into(), by.into());
+    }
+
+    pub fn uninvite(&mut self, user: &str, room: &str) {
+        if let Some(invites) = self.invites.get_mut(user) {
+            invites.remove(room);
+            if invites.is_empty() {
+                self.invites.remove(user);
+            }
+        }
+    }
+
+    /// Forget every invite to `room`, once it's no longer private
+    pub fn clear_invites(&mut self, room: &str) {
+        self.invites.retain(|_, invites| {
+            invites.remove(room);
+            !invites.is_empty()
+        });
+    }
+
+    pub fn is_invited(&self, user: &str, room: &str) -> bool {
+        self.invites
+            .get(user)
+            .is_some_and(|invites| invites.contains_key(room))
+    }
+
+    pub fn rooms<'a>(&'a self, user: &str) -> impl Iterator<Item = &'a String> {
+        self.rooms.get(user).into_iter().flatten()
+    }
+
+    /// Rooms `user` has an invite waiting for, with who sent it
+    pub fn invites<'a>(&'a self, user: &str) -> impl Iterator<Item = (&'a String, &'a String)> {
+        self.invites.get(user).into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joining_uses_up_invites() {
+        let mut memberships = Memberships::default();
+        memberships.invite("bob", "secret", "alice");
+        memberships.invite("bob", "other", "alice");
+        memberships.join("bob", "lobby");
+        assert!(memberships.is_invited("bob", "secret"));
+        memberships.join("bob", "secret");
+        assert!(!memberships.is_invited("bob", "secret"));
+
+        let rooms: Vec<_> = memberships.rooms("bob").collect();
+        assert_eq!(rooms, ["lobby", "secret"]);
+        let invites: Vec<_> = memberships.invites("bob").collect();
+        assert_eq!(invites, [(&"other".to_owned(), &"alice".to_owned())]);
+
+        memberships.leave("bob", "lobby");
+        memberships.leave("bob", "secret");
+        memberships.clear_invites("other");
+        assert_eq!(memberships.rooms("bob").count(), 0);
+        assert_eq!(memberships.invites("bob").count(), 0);
+    }
+}
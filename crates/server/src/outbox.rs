@@ -0,0 +1,214 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use common::commands::Response;
+use tokio::sync::Notify;
+
+use crate::config::SlowConsumer;
+
+/// Counters shared by every connection
+#[derive(Debug, Default)]
+pub struct Metrics {
+    dropped_messages: AtomicU64,
+}
+
+impl Metrics {
+    /// Responses thrown away because a connection couldn't keep up
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    fn dropped(&self, count: usize) -> u64 {
+        let count = count as u64;
+        self.dropped_messages.fetch_add(count, Ordering::Relaxed) + count
+    }
+}
+
+/// Responses waiting to be written to a connection, bounded so a client that
+/// stops reading can't grow the server's memory without limit
+#[derive(Debug)]
+struct Queue {
+    responses: VecDeque<Response>,
+    /// Set when the connection is gone, nothing more is queued
+    closed: bool,
+    /// Set when the queue filled up and the connection has to be dropped
+    overflowed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    depth: usize,
+    policy: SlowConsumer,
+    metrics: Arc<Metrics>,
+}
+
+/// Queues responses for a connection, never blocks or fails
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+/// Takes responses off the queue to write them to the connection
+#[derive(Debug)]
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+/// The queue filled up with [`SlowConsumer::Disconnect`] and the connection
+/// should be dropped
+#[derive(Debug, PartialEq, Eq)]
+pub struct Overflowed;
+
+pub fn channel(depth: usize, policy: SlowConsumer, metrics: Arc<Metrics>) -> (Outbox, Receiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            responses: VecDeque::new(),
+            closed: false,
+            overflowed: false,
+        }),
+        notify: Notify::new(),
+        depth,
+        policy,
+        metrics,
+    });
+
+    let outbox = Outbox {
+        shared: shared.clone(),
+    };
+    (outbox, Receiver { shared })
+}
+
+impl Outbox {
+    /// Queue a response, applying the slow consumer policy if the queue is
+    /// full. Responses to a connection that's gone are ignored
+    pub fn send(&self, response: Response) {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.closed || queue.overflowed {
+            return;
+        }
+
+        if queue.responses.len() >= shared.depth {
+            let dropped = match shared.policy {
+                SlowConsumer::DropOldest => {
+                    queue.responses.pop_front();
+                    1
+                }
+                SlowConsumer::Disconnect => {
+                    queue.overflowed = true;
+                    let dropped = queue.responses.len() + 1;
+                    queue.responses.clear();
+                    dropped
+                }
+            };
+            let total = shared.metrics.dropped(dropped);
+            tracing::warn!(dropped, total, "outbound queue full");
+
+            if queue.overflowed {
+                drop(queue);
+                shared.notify.notify_one();
+                return;
+            }
+        }
+
+        queue.responses.push_back(response);
+        drop(queue);
+        shared.notify.notify_one();
+    }
+}
+
+impl Receiver {
+    /// Wait for the next response to write
+    pub async fn recv(&mut self) -> Result<Response, Overflowed> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.overflowed {
+                    return Err(Overflowed);
+                }
+                if let Some(response) = queue.responses.pop_front() {
+                    return Ok(response);
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Take the next response without waiting, none if there isn't one
+    pub fn try_recv(&mut self) -> Result<Option<Response>, Overflowed> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.overflowed {
+            return Err(Overflowed);
+        }
+        Ok(queue.responses.pop_front())
+    }
+
+    /// Whether the queue filled up with [`SlowConsumer::Disconnect`]
+    pub fn is_overflowed(&self) -> bool {
+        self.shared.queue.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        queue.responses.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(policy: SlowConsumer) -> (Outbox, Receiver, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let (outbox, rx) = super::channel(2, policy, metrics.clone());
+        (outbox, rx, metrics)
+    }
+
+    fn len(rx: &Receiver) -> usize {
+        rx.shared.queue.lock().unwrap().responses.len()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() {
+        let (outbox, mut rx, metrics) = channel(SlowConsumer::DropOldest);
+        outbox.send(Response::ListUsers { users: vec![] });
+        outbox.send(Response::KeepAlive);
+        outbox.send(Response::KeepAlive);
+
+        assert_eq!(len(&rx), 2);
+        assert_eq!(metrics.dropped_messages(), 1);
+        assert!(matches!(rx.recv().await, Ok(Response::KeepAlive)));
+    }
+
+    #[tokio::test]
+    async fn disconnect_when_full() {
+        let (outbox, mut rx, metrics) = channel(SlowConsumer::Disconnect);
+        for _ in 0..3 {
+            outbox.send(Response::KeepAlive);
+        }
+
+        assert_eq!(rx.recv().await.unwrap_err(), Overflowed);
+        assert_eq!(metrics.dropped_messages(), 3);
+    }
+
+    #[test]
+    fn send_after_receiver_dropped() {
+        let (outbox, rx, metrics) = channel(SlowConsumer::Disconnect);
+        drop(rx);
+        for _ in 0..3 {
+            outbox.send(Response::KeepAlive);
+        }
+
+        assert_eq!(metrics.dropped_messages(), 0);
+    }
+}
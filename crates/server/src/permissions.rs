@@ -0,0 +1,39 @@
+use common::commands::Role;
+
+/// Something a user can try to do in a room, checked with
+/// [`Room::check`](crate::room::Room::check) before a command touches the room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Send a message to everyone in the room
+    Send,
+    /// Fetch older messages sent to the room
+    ReadHistory,
+    SetTopic,
+    SetPrivate,
+    Invite,
+    /// Kick or ban members ranked below them
+    Moderate,
+    Unban,
+    /// Grant or revoke operator
+    SetOperator,
+}
+
+impl Permission {
+    /// Whether only current members of the room can do this
+    pub fn requires_membership(self) -> bool {
+        matches!(self, Permission::Send | Permission::ReadHistory)
+    }
+
+    /// Least privileged role allowed to do this
+    pub fn required_role(self) -> Role {
+        match self {
+            Permission::Send | Permission::ReadHistory => Role::Member,
+            Permission::SetTopic
+            | Permission::SetPrivate
+            | Permission::Invite
+            | Permission::Moderate
+            | Permission::Unban => Role::Operator,
+            Permission::SetOperator => Role::Owner,
+        }
+    }
+}
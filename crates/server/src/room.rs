@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+
+use common::commands::{Member, Response, ResponseError, Role, RoomInfo, Status};
+
+use crate::permissions::Permission;
+
+/// A chat room, its members and who moderates it
+#[derive(Debug)]
+pub struct Room {
+    name: String,
+    topic: Option<String>,
+    owner: String,
+    operators: HashSet<String>,
+    members: HashSet<String>,
+    banned: HashSet<String>,
+    /// Hidden from room lists and only joinable by invite or key
+    private: bool,
+    key: Option<String>,
+}
+
+impl Room {
+    /// Create a room owned by the user creating it
+    pub fn new(name: String, owner: String) -> Self {
+        Self {
+            name,
+            topic: None,
+            owner,
+            operators: HashSet::default(),
+            members: HashSet::default(),
+            banned: HashSet::default(),
+            private: false,
+            key: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &String> {
+        self.members.iter()
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.members.contains(user)
+    }
+
+    pub fn is_banned(&self, user: &str) -> bool {
+        self.banned.contains(user)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Check `user` may join, banned users never can and private rooms need
+    /// an invite or the key unless they moderate the room. Invites are kept
+    /// with the user's memberships so `invited` says whether they have one
+    pub fn check_join(
+        &self,
+        user: &str,
+        invited: bool,
+        key: Option<&str>,
+    ) -> Result<(), ResponseError> {
+        if self.is_banned(user) {
+            return Err(ResponseError::BannedFromRoom(self.name.clone()));
+        }
+
+        let allowed = !self.private
+            || self.contains(user)
+            || invited
+            || self.role(user) >= Role::Operator
+            || (self.key.is_some() && self.key.as_deref() == key);
+        if allowed {
+            Ok(())
+        } else {
+            Err(ResponseError::Forbidden(self.name.clone()))
+        }
+    }
+
+    /// Returns whether the user wasn't already a member
+    pub fn join(&mut self, user: String) -> bool {
+        self.members.insert(user)
+    }
+
+    /// Returns whether the user was a member
+    pub fn leave(&mut self, user: &str) -> bool {
+        self.members.remove(user)
+    }
+
+    pub fn role(&self, user: &str) -> Role {
+        if self.owner == user {
+            Role::Owner
+        } else if self.operators.contains(user) {
+            Role::Operator
+        } else {
+            Role::Member
+        }
+    }
+
+    /// Check `user` has `permission` in this room
+    pub fn check(&self, user: &str, permission: Permission) -> Result<(), ResponseError> {
+        if permission.requires_membership() && !self.contains(user) {
+            return Err(self.not_in_room(user));
+        }
+
+        match permission.required_role() {
+            required if self.role(user) >= required => Ok(()),
+            Role::Owner => Err(ResponseError::NotRoomOwner(self.name.clone())),
+            _ => Err(ResponseError::NotRoomOperator(self.name.clone())),
+        }
+    }
+
+    pub fn set_operator(&mut self, user: &str, operator: bool) -> Result<(), ResponseError> {
+        if !self.contains(user) {
+            return Err(self.not_in_room(user));
+        }
+        if user == self.owner {
+            return Err(self.cannot_moderate(user));
+        }
+
+        if operator {
+            self.operators.insert(user.into());
+        } else {
+            self.operators.remove(user);
+        }
+        Ok(())
+    }
+
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = Some(topic).filter(|t| !t.is_empty());
+    }
+
+    pub fn set_private(&mut self, private: bool, key: Option<String>) {
+        self.private = private;
+        self.key = key.filter(|k| private && !k.is_empty());
+    }
+
+    /// Check `actor` outranks `user` so is allowed to kick or ban them
+    pub fn check_outranks(&self, actor: &str, user: &str) -> Result<(), ResponseError> {
+        if self.role(actor) <= self.role(user) {
+            Err(self.cannot_moderate(user))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Ban a user, also removing them from the room and operators.
+    /// Returns whether they were a member
+    pub fn ban(&mut self, user: String) -> bool {
+        self.operators.remove(&user);
+        let was_member = self.members.remove(&user);
+        self.banned.insert(user);
+        was_member
+    }
+
+    pub fn unban(&mut self, user: &str) {
+        self.banned.remove(user);
+    }
+
+    /// Everyone in the room along with their role and `status`
+    pub fn list_members(&self, status: impl Fn(&str) -> Status) -> Response {
+        let users = self
+            .members
+            .iter()
+            .map(|user| Member {
+                username: user.clone(),
+                role: self.role(user),
+                status: status(user),
+            })
+            .collect();
+
+        Response::ListMembers {
+            room: self.name.clone(),
+            topic: self.topic.clone(),
+            private: self.private,
+            users,
+            banned: self.banned.iter().cloned().collect(),
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            name: self.name.clone(),
+            topic: self.topic.clone(),
+            private: self.private,
+        }
+    }
+
+    fn not_in_room(&self, user: &str) -> ResponseError {
+        ResponseError::UserNotInRoom {
+            user: user.into(),
+            room: self.name.clone(),
+        }
+    }
+
+    fn cannot_moderate(&self, user: &str) -> ResponseError {
+        ResponseError::CannotModerate {
+            user: user.into(),
+            room: self.name.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room() -> Room {
+        let mut room = Room::new("room".into(), "owner".into());
+        for user in ["owner", "op", "alice", "bob"] {
+            room.join(user.into());
+        }
+        room.set_operator("op", true).unwrap();
+        room
+    }
+
+    #[test]
+    fn roles() {
+        let room = room();
+        assert_eq!(room.role("owner"), Role::Owner);
+        assert_eq!(room.role("op"), Role::Operator);
+        assert_eq!(room.role("alice"), Role::Member);
+    }
+
+    #[test]
+    fn only_owner_grants_operator() {
+        let mut room = room();
+        assert!(matches!(
+            room.check("op", Permission::SetOperator),
+            Err(ResponseError::NotRoomOwner(_))
+        ));
+        assert!(matches!(
+            room.set_operator("owner", false),
+            Err(ResponseError::CannotModerate { .. })
+        ));
+    }
+
+    #[test]
+    fn members_only_send() {
+        let room = room();
+        assert!(room.check("alice", Permission::Send).is_ok());
+        assert!(matches!(
+            room.check("carol", Permission::Send),
+            Err(ResponseError::UserNotInRoom { .. })
+        ));
+    }
+
+    #[test]
+    fn moderate_lower_roles_only() {
+        let room = room();
+        assert!(room.check_outranks("op", "alice").is_ok());
+        assert!(room.check_outranks("owner", "op").is_ok());
+        assert!(matches!(
+            room.check("alice", Permission::Moderate),
+            Err(ResponseError::NotRoomOperator(_))
+        ));
+        assert!(matches!(
+            room.check_outranks("op", "owner"),
+            Err(ResponseError::CannotModerate { .. })
+        ));
+    }
+
+    #[test]
+    fn ban_removes_member_and_operator() {
+        let mut room = room();
+        assert!(room.ban("op".into()));
+        assert!(!room.contains("op"));
+        assert!(room.is_banned("op"));
+        assert_eq!(room.role("op"), Role::Member);
+
+        room.unban("op");
+        assert!(!room.is_banned("op"));
+    }
+
+    #[test]
+    fn private_needs_invite_or_key() {
+        let mut room = room();
+        room.set_private(true, Some("hunter2".into()));
+
+        assert!(room.check_join("alice", false, None).is_ok());
+        assert!(matches!(
+            room.check_join("carol", false, None),
+            Err(ResponseError::Forbidden(_))
+        ));
+        assert!(room.check_join("carol", false, Some("hunter2")).is_ok());
+        assert!(room.check_join("dave", true, None).is_ok());
+
+        room.ban("dave".into());
+        assert!(matches!(
+            room.check_join("dave", true, None),
+            Err(ResponseError::BannedFromRoom(_))
+        ));
+    }
+
+    #[test]
+    fn operators_set_topic() {
+        let mut room = room();
+        assert!(matches!(
+            room.check("alice", Permission::SetTopic),
+            Err(ResponseError::NotRoomOperator(_))
+        ));
+        assert!(room.check("op", Permission::SetTopic).is_ok());
+
+        room.set_topic("deploys".into());
+        assert_eq!(room.info().topic.as_deref(), Some("deploys"));
+
+        room.set_topic("".into());
+        assert_eq!(room.info().topic, None);
+    }
+}
@@ -1,81 +1,195 @@
-use std::{io, time::Duration};
+use std::{future::Future, io, net::SocketAddr, time::Duration};
 
 use common::{
-    commands::{Command, KeepAlive, Kill, Response, KEEP_ALIVE_CHECK, KEEP_ALIVE_INTERVAL},
-    connection::Connection,
-    frame::Frame,
-    Result,
+    commands::{Command, Kill, LeaveReason, Response, ResponseError},
+    connection::{Connection, Stream},
+    frame::{Frame, FrameError, MAX_FRAME_SIZE},
+    tls::{self, TlsAcceptor},
+    Error, Result,
 };
 use tokio::{
     net::{TcpListener, ToSocketAddrs},
-    sync::{mpsc, watch},
+    sync::mpsc,
+    time::Instant,
 };
 use tracing::instrument;
 
-use crate::state::{Peer, ResponseType, ServerState};
+use crate::{
+    config::{Config, SlowConsumer},
+    outbox::{self, Overflowed},
+    state::{Peer, ResponseType, ServerState},
+    websocket::WsConnection,
+};
+
+/// How often sessions are checked for having outlived their resume grace
+const EXPIRE_SESSIONS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a client that's too slow gets to read why it's being dropped
+const TOO_SLOW_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct Server {
     listener: TcpListener,
+    ws_listener: Option<TcpListener>,
+    state: ServerState,
+    tls: Option<TlsAcceptor>,
+    keep_alive_interval: Duration,
+    keep_alive_timeout: Duration,
+    outbound_queue: usize,
+    slow_consumer: SlowConsumer,
+}
+
+/// A connection commands are read from and responses written to
+pub trait Transport: Send {
+    fn read_frame(&mut self) -> impl Future<Output = Result<Option<Frame>>> + Send;
+    fn write_frame(&mut self, frame: &Frame) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<S: Stream> Transport for Connection<S> {
+    async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        Connection::read_frame(self).await
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        Ok(Connection::write_frame(self, frame).await?)
+    }
 }
 
-pub struct Handler {
-    connection: Connection,
+/// Whether a connection has logged in, only handshakes are accepted before
+/// and everything but handshakes after
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    NotAuthenticated,
+    Authenticated,
+}
+
+impl ConnectionState {
+    fn check(self, command: &Command) -> std::result::Result<(), ResponseError> {
+        match (self, command.is_handshake()) {
+            (ConnectionState::NotAuthenticated, false) => Err(ResponseError::NotAuthenticated),
+            (ConnectionState::Authenticated, true) => Err(ResponseError::AlreadyAuthenticated),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct Handler<T> {
+    connection: T,
+    connection_state: ConnectionState,
     state: ServerState,
-    rx: mpsc::UnboundedReceiver<Response>,
-    keep_alive_rx: watch::Receiver<KeepAlive>,
-    keep_alive_kill_rx: mpsc::UnboundedReceiver<Kill>,
+    rx: outbox::Receiver,
+    kill_rx: mpsc::UnboundedReceiver<Kill>,
+    keep_alive_interval: Duration,
+    keep_alive_timeout: Duration,
 }
 
 impl Server {
-    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+    pub async fn bind(addr: impl ToSocketAddrs, config: Config) -> Result<Self> {
         let listener = TcpListener::bind(addr).await?;
+        let state = ServerState::new(&config)?;
 
-        Ok(Self { listener })
+        let ws_listener = match config.ws_address {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            listener,
+            ws_listener,
+            tls,
+            keep_alive_interval: config.keep_alive_interval,
+            keep_alive_timeout: config.keep_alive_timeout,
+            outbound_queue: config.outbound_queue,
+            slow_consumer: config.slow_consumer,
+            state,
+        })
     }
 
-    pub async fn listen(&self) -> io::Result<()> {
-        tracing::info!("accepting connections at {}", self.listener.local_addr()?);
+    /// Address plain connections are accepted on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
 
-        let state = ServerState::default();
+    pub async fn listen(&self) -> io::Result<()> {
+        let tls = if self.tls.is_some() { " over tls" } else { "" };
+        tracing::info!("accepting connections at {}{tls}", self.listener.local_addr()?);
+        if let Some(ws_listener) = &self.ws_listener {
+            tracing::info!(
+                "accepting websocket connections at {}{tls}",
+                ws_listener.local_addr()?
+            );
+        }
 
-        // Broadcast keep alive
-        let (keep_alive_tx, keep_alive_rx) = watch::channel(KeepAlive);
+        // End sessions that weren't resumed
+        let expire_state = self.state.clone();
         tokio::spawn(async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(KEEP_ALIVE_INTERVAL)).await;
-                // Comment out to see client exit if it doesn't get keep alive
-                keep_alive_tx.send(KeepAlive).unwrap();
+                tokio::time::sleep(EXPIRE_SESSIONS_INTERVAL).await;
+                expire_state.expire_sessions();
             }
         });
 
-        // Kick clients that haven't kept alive
-        let keep_alive_state = state.clone();
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(KEEP_ALIVE_CHECK)).await;
-                keep_alive_state.kick_keep_alive();
+        // handle incoming connections
+        match &self.ws_listener {
+            Some(ws_listener) => {
+                tokio::try_join!(
+                    self.accept(&self.listener, false),
+                    self.accept(ws_listener, true)
+                )?;
+                Ok(())
             }
-        });
+            None => self.accept(&self.listener, false).await,
+        }
+    }
 
-        // handle incoming connections
+    async fn accept(&self, listener: &TcpListener, websocket: bool) -> io::Result<()> {
         loop {
-            let (socket, addr) = self.listener.accept().await?;
+            let (socket, addr) = listener.accept().await?;
             tracing::info!("received connection from {addr}");
 
-            let (tx, rx) = mpsc::unbounded_channel();
-            let (keep_alive_kill_tx, keep_alive_kill_rx) = mpsc::unbounded_channel();
-            let peer = Peer::new(addr, tx, keep_alive_kill_tx);
+            let metrics = self.state.metrics();
+            let (tx, rx) = outbox::channel(self.outbound_queue, self.slow_consumer, metrics);
+            let (kill_tx, kill_rx) = mpsc::unbounded_channel();
+            let peer = Peer::new(addr, tx, kill_tx);
 
-            let mut handler = Handler {
-                connection: Connection::new(socket),
-                state: state.clone(),
-                rx,
-                keep_alive_rx: keep_alive_rx.clone(),
-                keep_alive_kill_rx,
-            };
+            let state = self.state.clone();
+            let tls = self.tls.clone();
+            let (interval, timeout) = (self.keep_alive_interval, self.keep_alive_timeout);
 
             tokio::spawn(async move {
-                if let Err(err) = handler.run(peer).await {
+                // Handshake in the task so a slow client can't hold up accepting others
+                let stream: Box<dyn Stream> = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(stream) => Box::new(stream),
+                        Err(err) => {
+                            tracing::error!(%err, "tls handshake failed with {addr}");
+                            return;
+                        }
+                    },
+                    None => Box::new(socket),
+                };
+
+                let result = if websocket {
+                    match WsConnection::accept(stream).await {
+                        Ok(connection) => {
+                            Handler::new(connection, state, rx, kill_rx, interval, timeout)
+                                .run(peer)
+                                .await
+                        }
+                        Err(err) => Err(err),
+                    }
+                } else {
+                    let connection = Connection::new(stream);
+                    Handler::new(connection, state, rx, kill_rx, interval, timeout)
+                        .run(peer)
+                        .await
+                };
+
+                if let Err(err) = result {
                     tracing::error!(%err);
                 }
             });
@@ -83,39 +197,114 @@ impl Server {
     }
 }
 
-impl Handler {
+impl<T: Transport> Handler<T> {
+    pub fn new(
+        connection: T,
+        state: ServerState,
+        rx: outbox::Receiver,
+        kill_rx: mpsc::UnboundedReceiver<Kill>,
+        keep_alive_interval: Duration,
+        keep_alive_timeout: Duration,
+    ) -> Self {
+        Self {
+            connection,
+            connection_state: ConnectionState::NotAuthenticated,
+            state,
+            rx,
+            kill_rx,
+            keep_alive_interval,
+            keep_alive_timeout,
+        }
+    }
+
     #[instrument(level = "info", name = "Handler::run", skip(self, peer), fields(peer_addr = %peer.addr()))]
     async fn run(&mut self, peer: Peer) -> Result<()> {
         tracing::trace!("started handling");
+        let result = self.handle(&peer).await;
+
+        // However the connection ended the session is detached so it can resume
+        let reason = match &result {
+            Ok(reason) => *reason,
+            Err(_) => LeaveReason::Disconnected,
+        };
+        self.state.remove_peer(&peer, reason);
+        result.map(|_| ())
+    }
+
+    /// Returns why the connection ended, for rooms to be told if it doesn't resume
+    async fn handle(&mut self, peer: &Peer) -> Result<LeaveReason> {
+        let start = Instant::now() + self.keep_alive_interval;
+        let mut keep_alive = tokio::time::interval_at(start, self.keep_alive_interval);
+        // Anything the client sends shows it's still there, not just keep alives
+        let mut last_seen = Instant::now();
+
         loop {
+            let deadline = last_seen + self.keep_alive_timeout;
             tokio::select! {
-                _ = self.keep_alive_kill_rx.recv() => {
+                _ = self.kill_rx.recv() => {
                     tracing::info!("killing");
-                    self.state.remove_peer(&peer);
                     break;
                 }
-                _ = self.keep_alive_rx.changed() => {
+                _ = tokio::time::sleep_until(deadline) => {
+                    tracing::info!("keep alive timed out");
+                    return Ok(LeaveReason::TimedOut);
+                }
+                _ = keep_alive.tick() => {
+                    // Comment out to see client reconnect if it doesn't get keep alive
                     let frame = Frame::from(Response::KeepAlive);
                     self.connection.write_frame(&frame).await?;
                 }
-                Some(res) = self.rx.recv() => {
+                res = self.rx.recv() => {
+                    let res = match res {
+                        Ok(res) => res,
+                        Err(Overflowed) => {
+                            tracing::warn!("disconnecting slow consumer");
+                            let frame = Frame::from(Response::Err(ResponseError::TooSlow));
+                            let write = self.connection.write_frame(&frame);
+                            let _ = tokio::time::timeout(TOO_SLOW_WRITE_TIMEOUT, write).await;
+                            break;
+                        }
+                    };
                     tracing::trace!("broadcast {res:?}");
                     let frame = Frame::from(res);
                     self.connection.write_frame(&frame).await?;
                 }
                 frame = self.connection.read_frame() => {
-                    let frame = match frame? {
-                        Some(frame) => frame,
-                        None => {
+                    last_seen = Instant::now();
+                    let frame = match frame {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => {
                             tracing::info!("connection closed");
-                            self.state.remove_peer(&peer);
                             break
                         }
+                        // What follows can't be trusted to line up with frames
+                        Err(Error::FrameError(FrameError::TooBig)) => {
+                            self.write_error(ResponseError::FrameTooBig(MAX_FRAME_SIZE)).await?;
+                            break
+                        }
+                        Err(err) => return Err(err),
                     };
 
-                    let command: Command = frame.raw().try_into()?;
+                    let command = match serde_json::from_str::<Command>(frame.raw()) {
+                        Ok(command) => command,
+                        Err(err) => {
+                            tracing::debug!(%err, "invalid command");
+                            self.write_error(ResponseError::InvalidCommand(err.to_string())).await?;
+                            continue
+                        }
+                    };
                     tracing::trace!("{command:?}");
-                    let response = self.state.apply(command, peer.clone());
+                    if let Err(err) = self.connection_state.check(&command) {
+                        self.write_error(err).await?;
+                        continue
+                    }
+
+                    let handshake = command.is_handshake();
+                    let response = self.state.apply(command, peer.clone()).await;
+                    // A rejected handshake is answered with an error, anything else logged in
+                    if handshake && !matches!(response, ResponseType::Sender(Response::Err(_))) {
+                        self.connection_state = ConnectionState::Authenticated;
+                    }
 
                     match response {
                         ResponseType::None => {},
@@ -123,17 +312,144 @@ impl Handler {
                             let frame = Frame::from(res);
                             self.connection.write_frame(&frame).await?;
                         },
-                        ResponseType::SenderAndUser(user, res) => {
-                            self.state.send(&user, res.clone());
-                            let frame = Frame::from(res);
-                            self.connection.write_frame(&frame).await?;
-                        }
                         ResponseType::Broadcast(res) => self.state.broadcast(res),
-                        ResponseType::BroadcastRoom(room, res) => self.state.broadcast_room(&room, res)
                     }
                 }
             }
         }
-        Ok(())
+        Ok(LeaveReason::Disconnected)
+    }
+
+    async fn write_error(&mut self, err: ResponseError) -> Result<()> {
+        let frame = Frame::from(Response::Err(err));
+        self.connection.write_frame(&frame).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use common::commands::{Handshake, Password};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// Handle a connection over an in memory stream, returning the client's end
+    fn connect(state: &ServerState, port: u16) -> Connection<DuplexStream> {
+        let keep_alive = Duration::from_secs(60);
+        connect_with_keep_alive(state, port, keep_alive, keep_alive)
+    }
+
+    fn connect_with_keep_alive(
+        state: &ServerState,
+        port: u16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Connection<DuplexStream> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (tx, rx) = outbox::channel(64, SlowConsumer::Disconnect, state.metrics());
+        let (kill_tx, kill_rx) = mpsc::unbounded_channel();
+        let peer = Peer::new((Ipv4Addr::LOCALHOST, port).into(), tx, kill_tx);
+
+        let connection = Connection::new(server);
+        let mut handler = Handler::new(connection, state.clone(), rx, kill_rx, interval, timeout);
+        tokio::spawn(async move { handler.run(peer).await });
+        Connection::new(client)
+    }
+
+    async fn request(client: &mut Connection<DuplexStream>, raw: &str) -> Response {
+        client.write_frame(&Frame::from(raw)).await.unwrap();
+        let frame = client.read_frame().await.unwrap().unwrap();
+        frame.raw().try_into().unwrap()
+    }
+
+    fn register(username: &str) -> String {
+        Command::Register {
+            username: username.into(),
+            password: Password("hunter2".into()),
+            handshake: Handshake::default(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn commands_need_handshake() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut client = connect(&state, 1);
+
+        let join = r#"{"JoinOrCreate":{"room":"room"}}"#;
+        let response = request(&mut client, join).await;
+        assert!(matches!(
+            response,
+            Response::Err(ResponseError::NotAuthenticated)
+        ));
+
+        // Still handling commands after something that isn't one
+        let response = request(&mut client, "{not json").await;
+        assert!(matches!(
+            response,
+            Response::Err(ResponseError::InvalidCommand(_))
+        ));
+        let response = request(&mut client, r#""ListRooms""#).await;
+        assert!(matches!(
+            response,
+            Response::Err(ResponseError::NotAuthenticated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn one_handshake_per_connection() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut client = connect(&state, 2);
+
+        let response = request(&mut client, &register("alice")).await;
+        assert!(matches!(response, Response::Welcome(_)));
+
+        let response = request(&mut client, &register("bob")).await;
+        assert!(matches!(
+            response,
+            Response::Err(ResponseError::AlreadyAuthenticated)
+        ));
+        let response = request(&mut client, r#""ListRooms""#).await;
+        assert!(matches!(response, Response::ListRooms { .. }));
+    }
+
+    #[tokio::test]
+    async fn silent_connections_time_out() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let interval = Duration::from_millis(100);
+        let timeout = Duration::from_secs(1);
+        let mut silent = connect_with_keep_alive(&state, 3, interval, timeout);
+        let mut active = connect_with_keep_alive(&state, 4, interval, timeout);
+
+        // Only hearing keep alives from the server doesn't keep the silent one open
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(Some(_)) = silent.read_frame().await {}
+        });
+        // Anything the client sends counts, even commands that get turned away
+        let talking = async {
+            for _ in 0..8 {
+                tokio::time::sleep(timeout / 4).await;
+                let keep_alive = Frame::from(Command::KeepAlive);
+                active.write_frame(&keep_alive).await.unwrap();
+            }
+        };
+        let (closed, _) = tokio::join!(closed, talking);
+        closed.expect("silent connection wasn't dropped");
+
+        // Well past its own deadline had it stayed quiet, but still answering
+        active.write_frame(&Frame::from("{not json")).await.unwrap();
+        loop {
+            let frame = active
+                .read_frame()
+                .await
+                .unwrap()
+                .expect("active connection was dropped");
+            let response = Response::try_from(frame.raw()).unwrap();
+            if matches!(response, Response::Err(ResponseError::InvalidCommand(_))) {
+                break;
+            }
+        }
     }
 }
@@ -1,244 +1,1965 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::{Duration, Instant},
 };
 
-use common::commands::{Command, Kill, Response, ResponseError, Target};
-use tokio::sync::mpsc;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 
-#[derive(Debug, Clone, Default)]
+use common::commands::{
+    Capability, Command, Handshake, Kill, LeaveReason, MessageId, Password, Response,
+    ResponseError, Status, Target, UserInfo, Welcome, CAPABILITIES, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::{
+    accounts::{dummy_hash, hash_password, verify_password, Accounts},
+    config::{Config, SlowConsumer},
+    history::{Conversation, Entry, History},
+    mailbox::Mailbox,
+    markers::ReadMarkers,
+    memberships::Memberships,
+    outbox::{self, Metrics, Outbox},
+    permissions::Permission,
+    room::Room,
+};
+
+/// Most messages returned by a single history fetch
+const MAX_FETCH_LIMIT: usize = 100;
+
+#[derive(Debug, Clone)]
 pub struct ServerState {
     shared: Arc<Shared>,
 }
 
-#[derive(Debug, Default)]
+/// Everything the server keeps track of, split up so a busy room doesn't hold
+/// up logins, direct messages or other rooms
+///
+/// Locks are always taken in this order to avoid deadlocks: a single room,
+/// then `memberships`, then `connections`, then `mailbox`, then `history`,
+/// then `read_markers`. The rooms map is only held long enough to look a room
+/// up, and two rooms are never locked at once.
+#[derive(Debug)]
 struct Shared {
-    state: Mutex<State>,
+    rooms: RwLock<HashMap<String, Arc<RoomEntry>>>,
+    /// Kept in step with the rooms while they're locked, so logging in and
+    /// ending sessions only have to look at the user's own rooms
+    memberships: Mutex<Memberships>,
+    connections: RwLock<Connections>,
+    mailbox: Mutex<Mailbox>,
+    history: Mutex<History>,
+    read_markers: Mutex<ReadMarkers>,
+    accounts: Mutex<Accounts>,
+    /// Each hash takes a lot of memory, so only as many run at once as
+    /// there are cores to run them
+    hashing: Semaphore,
+    metrics: Arc<Metrics>,
+    settings: Settings,
+}
+
+/// Config the state needs once the server is running
+#[derive(Debug)]
+struct Settings {
+    backlog: usize,
+    resume_grace: Duration,
+    keep_alive_interval: Duration,
+    keep_alive_timeout: Duration,
+    server_name: String,
+    motd: Option<String>,
+    outbound_queue: usize,
+    slow_consumer: SlowConsumer,
 }
 
 #[derive(Debug, Clone)]
 pub struct Peer {
     addr: SocketAddr,
-    tx: mpsc::UnboundedSender<Response>,
-    keep_alive: bool,
+    tx: Outbox,
     kill_tx: mpsc::UnboundedSender<Kill>,
+    capabilities: Vec<Capability>,
 }
 
 impl Peer {
-    pub fn new(
-        addr: SocketAddr,
-        tx: mpsc::UnboundedSender<Response>,
-        kill_tx: mpsc::UnboundedSender<Kill>,
-    ) -> Self {
+    pub fn new(addr: SocketAddr, tx: Outbox, kill_tx: mpsc::UnboundedSender<Kill>) -> Self {
         Self {
             addr,
             tx,
-            keep_alive: true,
             kill_tx,
+            capabilities: Vec::new(),
         }
     }
 
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Whether the peer agreed to `capability` in its handshake
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Queue `response` for the peer, unless it needs a capability the peer
+    /// didn't agree to
+    fn send(&self, response: Response) {
+        if understands(&self.capabilities, &response) {
+            self.tx.send(response);
+        }
+    }
+}
+
+/// One of a user's logins, kept across reconnects so their rooms survive
+/// the connection dropping until the resume grace period passes
+#[derive(Debug)]
+struct Session {
+    token: String,
+    /// Connection the session is attached to, none if it dropped
+    addr: Option<SocketAddr>,
+    /// When and why the connection dropped, if it has
+    detached: Option<(Instant, LeaveReason)>,
+    /// Agreed on when the session started, what's missed is held on to
+    /// for the connection that resumes it
+    capabilities: Vec<Capability>,
+    /// Where the session's responses go, its connection's outbox or once
+    /// detached the queue `missed` reads from
+    tx: Outbox,
+    /// Sent to the user while they were detached, bounded like an outbox
+    /// so a session nobody resumes can't grow without limit
+    missed: Option<outbox::Receiver>,
+    /// Whether the client said it's been left alone
+    idle: bool,
+}
+
+impl Session {
+    /// Whether the session should be ended, it wasn't resumed in time or
+    /// missed more than it could hold on to
+    fn expired(&self, grace: Duration) -> bool {
+        let overflowed = self.missed.as_ref().is_some_and(|rx| rx.is_overflowed());
+        let timed_out = self.detached.is_some_and(|(at, _)| at.elapsed() >= grace);
+        overflowed || timed_out
+    }
+}
+
+/// A room and what's waiting to be sent to its members
+#[derive(Debug)]
+struct RoomEntry {
+    room: Mutex<Room>,
+    /// Queued while the room is locked, in the order things happened in it,
+    /// and sent once it's unlocked so a busy room isn't held while sending
+    pending: Mutex<VecDeque<(Vec<Outbox>, Response)>>,
+    /// Held while sending what's pending so members get it all in order
+    sending: Mutex<()>,
+}
+
+impl RoomEntry {
+    fn new(room: Room) -> Self {
+        Self {
+            room: Mutex::new(room),
+            pending: Mutex::default(),
+            sending: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> LockedRoom<'_> {
+        LockedRoom {
+            entry: self,
+            room: Some(self.room.lock().unwrap()),
+        }
+    }
+
+    /// Send everything queued so far. Whoever queued something sends after
+    /// unlocking the room, so it's either sent here or already was
+    fn deliver(&self) {
+        let _sending = self.sending.lock().unwrap();
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (outboxes, response) in pending {
+            for outbox in outboxes {
+                outbox.send(response.clone());
+            }
+        }
+    }
+}
+
+/// A locked room, what's queued for its members is sent once it's unlocked
+struct LockedRoom<'a> {
+    entry: &'a RoomEntry,
+    room: Option<MutexGuard<'a, Room>>,
+}
+
+impl LockedRoom<'_> {
+    fn queue(&self, outboxes: Vec<Outbox>, response: Response) {
+        let mut pending = self.entry.pending.lock().unwrap();
+        pending.push_back((outboxes, response));
+    }
 }
 
+impl Deref for LockedRoom<'_> {
+    type Target = Room;
+
+    fn deref(&self) -> &Room {
+        self.room.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for LockedRoom<'_> {
+    fn deref_mut(&mut self) -> &mut Room {
+        self.room.as_mut().unwrap()
+    }
+}
+
+impl Drop for LockedRoom<'_> {
+    fn drop(&mut self) {
+        drop(self.room.take());
+        self.entry.deliver();
+    }
+}
+
+/// Who is logged in and on which connections
 #[derive(Debug, Default)]
-struct State {
+struct Connections {
     addr_to_user: HashMap<SocketAddr, String>,
-    users: HashMap<String, Peer>,
-    rooms: HashMap<String, HashSet<String>>,
+    /// Every connection each online user is logged in on
+    users: HashMap<String, HashMap<SocketAddr, Peer>>,
+    sessions: HashMap<String, Vec<Session>>,
+    /// Statuses users set, kept until their last session ends
+    statuses: HashMap<String, (Status, Option<String>)>,
 }
 
 pub enum ResponseType {
     None,
     Sender(Response),
-    SenderAndUser(String, Response),
     Broadcast(Response),
-    BroadcastRoom(String, Response),
 }
 
-impl State {
-    fn hello(&mut self, username: String, peer: Peer) -> ResponseType {
-        let addr = peer.addr;
-        if self.users.insert(username.clone(), peer).is_some() {
-            return ResponseType::Sender(Response::Err(ResponseError::UserAlreadyExists(username)));
+/// Errors are only ever sent back to whoever caused them
+impl From<ResponseError> for ResponseType {
+    fn from(err: ResponseError) -> Self {
+        ResponseType::Sender(Response::Err(err))
+    }
+}
+
+impl Connections {
+    /// Every connection `user` is logged in on
+    fn peers<'a>(&'a self, user: &str) -> impl Iterator<Item = &'a Peer> {
+        self.users.get(user).into_iter().flat_map(HashMap::values)
+    }
+
+    fn is_online(&self, user: &str) -> bool {
+        self.users.contains_key(user)
+    }
+
+    fn status(&self, user: &str) -> Status {
+        self.user_info(user).status
+    }
+
+    /// How available `user` is shown as, what they set unless they said
+    /// they're online and have left every session idle
+    fn user_info(&self, user: &str) -> UserInfo {
+        let (mut status, note) = self.statuses.get(user).cloned().unwrap_or_default();
+        if status == Status::Online && self.is_idle(user) {
+            status = Status::Away;
+        }
+        UserInfo {
+            username: user.into(),
+            status,
+            note,
         }
+    }
 
-        self.addr_to_user.insert(addr, username);
-        ResponseType::None
+    /// Whether every session with a connection says it's idle
+    fn is_idle(&self, user: &str) -> bool {
+        let sessions = self.sessions.get(user).into_iter().flatten();
+        let mut attached = sessions.filter(|s| s.addr.is_some()).peekable();
+        attached.peek().is_some() && attached.all(|s| s.idle)
+    }
+
+    /// What to tell everyone if how a user's shown changed from `before`
+    fn status_change(&self, before: UserInfo) -> Option<Response> {
+        let after = self.user_info(&before.username);
+        (after != before).then_some(Response::StatusChanged {
+            username: after.username,
+            status: after.status,
+            note: after.note,
+        })
+    }
+
+    /// Forget one of a user's connections, they're offline once it was the last
+    fn remove_connection(&mut self, user: &str, addr: SocketAddr) -> Option<Peer> {
+        let peers = self.users.get_mut(user)?;
+        let peer = peers.remove(&addr);
+        if peers.is_empty() {
+            self.users.remove(user);
+        }
+        peer
+    }
+
+    /// Detach a session from a closed connection, it's kept until it expires
+    /// in case they resume, with what it misses going to `missed`. Returns
+    /// the user if they're now offline
+    fn remove_peer(
+        &mut self,
+        peer: &Peer,
+        reason: LeaveReason,
+        (tx, missed): (Outbox, outbox::Receiver),
+    ) -> Option<String> {
+        // Nothing to do if they never said hello or a resume already took over
+        let user = self.addr_to_user.remove(&peer.addr)?;
+        self.remove_connection(&user, peer.addr);
+
+        let mut sessions = self.sessions.get_mut(&user).into_iter().flatten();
+        if let Some(session) = sessions.find(|s| s.addr == Some(peer.addr)) {
+            session.addr = None;
+            session.detached = Some((Instant::now(), reason));
+            session.tx = tx;
+            session.missed = Some(missed);
+        }
+        (!self.is_online(&user)).then_some(user)
+    }
+
+    /// End sessions detached for longer than `grace` or that missed too
+    /// much, returning the users whose last session ended and why its
+    /// connection dropped
+    fn expire_sessions(&mut self, grace: Duration) -> Vec<(String, LeaveReason)> {
+        let mut expired = Vec::new();
+        self.sessions.retain(|user, sessions| {
+            let mut reason = LeaveReason::Disconnected;
+            sessions.retain(|s| match s.detached {
+                Some((_, why)) if s.expired(grace) => {
+                    reason = why;
+                    false
+                }
+                _ => true,
+            });
+
+            if sessions.is_empty() {
+                expired.push((user.clone(), reason));
+            }
+            !sessions.is_empty()
+        });
+
+        for (user, _) in &expired {
+            self.statuses.remove(user);
+        }
+        expired
     }
 
-    fn join_or_create(&mut self, room: String, user: SocketAddr) -> ResponseType {
-        let user = self.user(user).into();
+    /// Send to every session of a user, holding on to it for the detached
+    /// ones to resume
+    fn send_to(&self, user: &str, response: Response) {
+        for outbox in self.outboxes(user, &response) {
+            outbox.send(response.clone());
+        }
+    }
 
-        let room_entry = self.rooms.entry(room.clone()).or_default();
-        room_entry.insert(user);
+    /// Where to send something meant for each of `user`'s sessions that
+    /// can read it, detached ones included
+    fn outboxes(&self, user: &str, response: &Response) -> Vec<Outbox> {
+        let sessions = self.sessions.get(user).into_iter().flatten();
+        let sessions = sessions.filter(|s| understands(&s.capabilities, response));
+        sessions.map(|s| s.tx.clone()).collect()
+    }
+
+    /// A connection `user` is logged in on
+    fn peer(&self, user: &str, addr: SocketAddr) -> Option<&Peer> {
+        self.users.get(user)?.get(&addr)
+    }
+}
 
-        let users = room_entry.iter().cloned().collect();
-        let response = Response::ListMembers {
-            room: room.clone(),
-            users,
+impl ServerState {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let history = match &config.history_path {
+            Some(path) => History::open(path)?,
+            None => History::default(),
+        };
+
+        let accounts = match &config.accounts_path {
+            Some(path) => Accounts::open(path)?,
+            None => Accounts::default(),
         };
 
-        ResponseType::BroadcastRoom(room, response)
+        let mailbox = match &config.mailbox_path {
+            Some(path) => Mailbox::open(path)?,
+            None => Mailbox::default(),
+        };
+
+        let settings = Settings {
+            backlog: config.backlog,
+            resume_grace: config.resume_grace,
+            keep_alive_interval: config.keep_alive_interval,
+            keep_alive_timeout: config.keep_alive_timeout,
+            server_name: config.name.clone(),
+            motd: config.motd.clone(),
+            outbound_queue: config.outbound_queue,
+            slow_consumer: config.slow_consumer,
+        };
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                rooms: RwLock::default(),
+                memberships: Mutex::default(),
+                connections: RwLock::default(),
+                mailbox: Mutex::new(mailbox),
+                history: Mutex::new(history),
+                read_markers: Mutex::default(),
+                accounts: Mutex::new(accounts),
+                hashing: Semaphore::new(
+                    std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+                ),
+                metrics: Arc::default(),
+                settings,
+            }),
+        })
     }
 
-    fn leave_room(&mut self, room: String, user: SocketAddr) -> ResponseType {
-        let user = self.user(user).to_owned();
+    /// For new connections' outboxes to add what they drop to
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.shared.metrics.clone()
+    }
 
-        if let Some(room_entry) = self.rooms.get_mut(&room) {
-            if room_entry.remove(&user) {
-                let users = room_entry.iter().cloned().collect();
-                let response = Response::ListMembers {
-                    room: room.clone(),
-                    users,
+    pub async fn apply(&self, command: Command, peer: Peer) -> ResponseType {
+        // Hashing passwords is slow so accounts are checked before locking the state
+        let negotiated = match &command {
+            Command::Hello {
+                username,
+                password,
+                handshake,
+            } => match negotiate(handshake) {
+                Ok(negotiated) => self.login(username, password).await.map(|_| negotiated),
+                Err(err) => Err(err),
+            },
+            Command::Register {
+                username,
+                password,
+                handshake,
+            } => match negotiate(handshake) {
+                Ok(negotiated) => self.register(username, password).await.map(|_| negotiated),
+                Err(err) => Err(err),
+            },
+            Command::Resume { handshake, .. } => negotiate(handshake),
+            _ => Ok((PROTOCOL_VERSION, Vec::new())),
+        };
+        let (version, capabilities) = match negotiated {
+            Ok(negotiated) => negotiated,
+            Err(err) => return err.into(),
+        };
+
+        // Direct messages can only go to registered users, though they may be offline
+        if let Command::Send {
+            target: Target::Username(username),
+            ..
+        } = &command
+        {
+            if !self.shared.accounts.lock().unwrap().contains(username) {
+                return ResponseError::UnknownUser(username.clone()).into();
+            }
+        }
+
+        let command = match command {
+            Command::Hello { username, .. } | Command::Register { username, .. } => {
+                return self.attach(username, None, peer, version, capabilities)
+            }
+            Command::Resume {
+                username, token, ..
+            } => return self.resume(username, token, peer, version, capabilities),
+            command => command,
+        };
+
+        // Handlers only pass on other commands once logged in, but a resume
+        // elsewhere may have just taken over this connection's session
+        let connections = self.shared.connections.read().unwrap();
+        let Some(username) = connections.addr_to_user.get(&peer.addr).cloned() else {
+            return ResponseError::NotAuthenticated.into();
+        };
+        // The peer kept at login knows what was agreed on in the handshake
+        let peer = connections
+            .peer(&username, peer.addr)
+            .cloned()
+            .unwrap_or(peer);
+        drop(connections);
+
+        if let Some(capability) = command.capability() {
+            if !peer.supports(capability) {
+                return ResponseError::NotNegotiated(capability).into();
+            }
+        }
+
+        match command {
+            Command::Hello { .. } | Command::Register { .. } | Command::Resume { .. } => {
+                unreachable!("handshakes are handled above")
+            }
+            Command::JoinOrCreate { room, key } => self.join_or_create(room, key, username),
+            Command::Leave { room } => self.leave_room(room, username),
+            // Handlers keep track of when they last heard from their connection
+            Command::KeepAlive => ResponseType::None,
+            Command::ListRooms => self.list_rooms(&username),
+            Command::ListUsers => self.list_users(),
+            Command::Send { target, message } => {
+                self.send_message(target, message, username, &peer)
+            }
+            Command::SetOperator {
+                room,
+                user,
+                operator,
+            } => self.set_operator(room, user, operator, username),
+            Command::Kick { room, user } => self.kick(room, user, username),
+            Command::Ban { room, user } => self.ban(room, user, username),
+            Command::Unban { room, user } => self.unban(room, user, username),
+            Command::SetPrivate { room, private, key } => {
+                self.set_private(room, private, key, username)
+            }
+            Command::Invite { room, user } => self.invite(room, user, username),
+            Command::SetTopic { room, topic } => self.set_topic(room, topic, username),
+            Command::SetStatus { status, note } => self.set_status(status, note, username),
+            Command::Idle { idle } => self.set_idle(idle, username, peer.addr),
+            Command::Typing { target } => self.typing(target, username),
+            Command::MarkRead { target, message_id } => {
+                self.mark_read(target, message_id, username)
+            }
+            Command::FetchHistory {
+                target,
+                before,
+                limit,
+            } => self.fetch_history(target, before, limit, &username),
+        }
+    }
+
+    /// Hashing runs on the blocking pool so a login doesn't hold up every
+    /// other connection on the same worker
+    async fn login(&self, username: &str, password: &Password) -> Result<(), ResponseError> {
+        let hash = self.shared.accounts.lock().unwrap().password_hash(username);
+        let known = hash.is_some();
+
+        // Unknown users are checked too so they can't be told apart by timing
+        let _permit = self.shared.hashing.acquire().await.unwrap();
+        let password = password.0.clone();
+        let verify = tokio::task::spawn_blocking(move || {
+            let hash = hash.as_deref().unwrap_or_else(|| dummy_hash());
+            verify_password(&password, hash)
+        });
+        match verify.await {
+            Ok(true) if known => Ok(()),
+            _ => Err(ResponseError::InvalidCredentials),
+        }
+    }
+
+    async fn register(&self, username: &str, password: &Password) -> Result<(), ResponseError> {
+        if self.shared.accounts.lock().unwrap().contains(username) {
+            return Err(ResponseError::AccountAlreadyExists(username.into()));
+        }
+
+        let _permit = self.shared.hashing.acquire().await.unwrap();
+        let password = password.0.clone();
+        let hash = tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .expect("hashing a password panicked");
+        let mut accounts = self.shared.accounts.lock().unwrap();
+        accounts.register(username.into(), hash)
+    }
+
+    fn resume(
+        &self,
+        username: String,
+        token: String,
+        peer: Peer,
+        version: u32,
+        capabilities: Vec<Capability>,
+    ) -> ResponseType {
+        let mut connections = self.shared.connections.write().unwrap();
+        let sessions = connections.sessions.get_mut(&username);
+        let Some(sessions) = sessions else {
+            return ResponseError::InvalidResumeToken.into();
+        };
+        // Sessions past their end are only still around until they're next expired
+        let grace = self.shared.settings.resume_grace;
+        let index = sessions
+            .iter()
+            .position(|s| s.token == token && !s.expired(grace));
+        let Some(index) = index else {
+            return ResponseError::InvalidResumeToken.into();
+        };
+        let session = sessions.remove(index);
+
+        // The old connection might not have been noticed as dead yet
+        if let Some(addr) = session.addr {
+            connections.addr_to_user.remove(&addr);
+            if let Some(old) = connections.remove_connection(&username, addr) {
+                let _ = old.kill_tx.send(Kill);
+            }
+        }
+        drop(connections);
+
+        self.attach(username, Some(session), peer, version, capabilities)
+    }
+
+    /// Start a new session for a user on a connection, or pick up `resumed`
+    fn attach(
+        &self,
+        username: String,
+        resumed: Option<Session>,
+        mut peer: Peer,
+        version: u32,
+        capabilities: Vec<Capability>,
+    ) -> ResponseType {
+        let shared = &*self.shared;
+        let settings = &shared.settings;
+        let addr = peer.addr;
+        peer.capabilities = capabilities.clone();
+
+        // Found without locking any rooms so a busy one can't hold up logging in
+        let memberships = shared.memberships.lock().unwrap();
+        let rooms: Vec<_> = memberships.rooms(&username).cloned().collect();
+        let invites: Vec<_> = memberships
+            .invites(&username)
+            .map(|(room, by)| Response::Invited {
+                room: room.clone(),
+                by: by.clone(),
+            })
+            .collect();
+        drop(memberships);
+
+        let mut connections = shared.connections.write().unwrap();
+        connections.addr_to_user.insert(addr, username.clone());
+
+        // The client still has everything from before a resumed session dropped
+        let replay_history = resumed.is_none();
+        let mut missed = Vec::new();
+        if let Some(mut rx) = resumed.and_then(|session| session.missed) {
+            while let Ok(Some(response)) = rx.try_recv() {
+                missed.push(response);
+            }
+        }
+        let token = resume_token();
+        let session = Session {
+            token: token.clone(),
+            addr: Some(addr),
+            detached: None,
+            capabilities: capabilities.clone(),
+            tx: peer.tx.clone(),
+            missed: None,
+            idle: false,
+        };
+        let before = connections.user_info(&username);
+        connections
+            .sessions
+            .entry(username.clone())
+            .or_default()
+            .push(session);
+        // Away for being idle everywhere else, but not in this new session
+        let status_change = connections
+            .status_change(before)
+            .filter(|_| connections.is_online(&username));
+
+        let welcome = Welcome {
+            version,
+            capabilities,
+            username: username.clone(),
+            server_name: settings.server_name.clone(),
+            motd: settings.motd.clone(),
+            keep_alive_interval: settings.keep_alive_interval.as_secs(),
+            keep_alive_timeout: settings.keep_alive_timeout.as_secs(),
+            rooms: rooms.clone(),
+            resume_token: token,
+            resume_grace: settings.resume_grace.as_secs(),
+        };
+
+        // The welcome has to arrive before anything replayed so the client
+        // knows the session is up
+        peer.send(Response::Welcome(welcome));
+
+        // Replay recent direct messages so open chats are restored, along with
+        // anything sent while offline that's older than the backlog
+        let mut mailbox = shared.mailbox.lock().unwrap();
+        let mut replay = BTreeMap::new();
+        if replay_history {
+            let history = shared.history.lock().unwrap();
+            for conversation in history.direct_conversations(&username) {
+                for entry in history.recent(conversation, settings.backlog) {
+                    replay.insert(entry.message.id, entry.response());
+                }
+            }
+        }
+        let delivered = mailbox.take(&username);
+        for entry in &delivered {
+            replay.insert(entry.message.id, entry.response());
+        }
+        for response in replay.into_values().chain(missed) {
+            peer.send(response);
+        }
+
+        for (recipient, ids) in mailbox.sent_by(&username) {
+            let username = recipient.into();
+            peer.send(Response::Queued { username, ids });
+        }
+        drop(mailbox);
+
+        let mut senders: HashMap<String, Vec<MessageId>> = HashMap::new();
+        for entry in delivered {
+            senders
+                .entry(entry.message.sender)
+                .or_default()
+                .push(entry.message.id);
+        }
+        for (sender, ids) in senders {
+            let response = Response::Delivered {
+                username: username.clone(),
+                ids,
+            };
+            connections.send_to(&sender, response);
+        }
+
+        for response in invites {
+            peer.send(response);
+        }
+
+        // Tell them which of the people they've had direct messages with are
+        // around, and tell those people they are too
+        let came_online = !connections.is_online(&username);
+        let history = shared.history.lock().unwrap();
+        for partner in history.partners(&username).filter(|p| **p != username) {
+            let online = connections.is_online(partner);
+            peer.send(Response::Presence {
+                username: partner.clone(),
+                online,
+            });
+            if came_online && online {
+                let response = Response::Presence {
+                    username: username.clone(),
+                    online: true,
                 };
+                connections.send_to(partner, response);
+            }
+        }
+        drop(history);
+
+        // Where they got up to, so only what's new shows as unread
+        let read_markers = shared.read_markers.lock().unwrap();
+        for (target, message_id) in read_markers.markers(&username) {
+            peer.send(Response::MarkedRead {
+                target: target.clone(),
+                message_id,
+            });
+        }
+        drop(read_markers);
 
-                ResponseType::BroadcastRoom(room, response)
-            } else {
-                ResponseType::Sender(Response::Err(ResponseError::UserNotInRoom { user, room }))
+        let tx = peer.tx.clone();
+        connections
+            .users
+            .entry(username.clone())
+            .or_default()
+            .insert(addr, peer);
+        drop(connections);
+
+        if let Some(response) = status_change {
+            self.broadcast(response);
+        }
+
+        // Rooms kept from before the connection dropped may have changed since.
+        // Their members are queued behind what's already going out to them,
+        // then recent messages for a session that doesn't have them yet
+        for room in rooms {
+            if let Some(room) = self.room(&room) {
+                let room = room.lock();
+                if !room.contains(&username) {
+                    continue;
+                }
+                room.queue(vec![tx.clone()], self.list_members(&room));
+                if replay_history {
+                    let conversation = Conversation::Room(room.name().into());
+                    let history = shared.history.lock().unwrap();
+                    for entry in history.recent(&conversation, settings.backlog) {
+                        room.queue(vec![tx.clone()], entry.response());
+                    }
+                }
             }
+        }
+
+        ResponseType::None
+    }
+
+    fn join_or_create(&self, room: String, key: Option<String>, user: String) -> ResponseType {
+        let room_entry = self
+            .shared
+            .rooms
+            .write()
+            .unwrap()
+            .entry(room.clone())
+            .or_insert_with(|| Arc::new(RoomEntry::new(Room::new(room.clone(), user.clone()))))
+            .clone();
+        let mut room_entry = room_entry.lock();
+
+        let mut memberships = self.shared.memberships.lock().unwrap();
+        let invited = memberships.is_invited(&user, &room);
+        if let Err(err) = room_entry.check_join(&user, invited, key.as_deref()) {
+            return err.into();
+        }
+        let joined = room_entry.join(user.clone());
+        memberships.join(&user, &room);
+        drop(memberships);
+        self.broadcast_members(&room_entry);
+
+        // Members list has to arrive first so the client knows about the room
+        if joined {
+            let response = Response::UserJoined {
+                room: room.clone(),
+                username: user.clone(),
+            };
+            let others = room_entry.members().filter(|m| **m != user);
+            self.fan_out(&room_entry, others, response);
+
+            let conversation = Conversation::Room(room);
+            let recent: Vec<_> = self
+                .shared
+                .history
+                .lock()
+                .unwrap()
+                .recent(&conversation, self.shared.settings.backlog)
+                .iter()
+                .map(Entry::response)
+                .collect();
+
+            for response in recent {
+                self.fan_out(&room_entry, std::iter::once(&user), response);
+            }
+        }
+
+        ResponseType::None
+    }
+
+    fn leave_room(&self, room: String, user: String) -> ResponseType {
+        let Some(room_entry) = self.room(&room) else {
+            return ResponseError::RoomDoesNotExist(room).into();
+        };
+        let mut room_entry = room_entry.lock();
+
+        if room_entry.leave(&user) {
+            self.shared.memberships.lock().unwrap().leave(&user, &room);
+            self.broadcast_members(&room_entry);
+            self.tell_left(&room_entry, &user, LeaveReason::Left);
+
+            // Their other sessions are no longer members so wouldn't hear otherwise
+            let response = Response::UserLeft {
+                room,
+                username: user.clone(),
+                reason: LeaveReason::Left,
+            };
+            self.fan_out(&room_entry, std::iter::once(&user), response);
+            ResponseType::None
         } else {
-            ResponseType::Sender(Response::Err(ResponseError::RoomDoesNotExist(room)))
+            ResponseError::UserNotInRoom { user, room }.into()
         }
     }
 
-    fn list_rooms(&self) -> ResponseType {
-        let rooms = self.rooms.keys().cloned().collect();
+    fn set_operator(
+        &self,
+        room: String,
+        target: String,
+        operator: bool,
+        user: String,
+    ) -> ResponseType {
+        self.with_room(&room, &user, Permission::SetOperator, |room_entry| {
+            if let Err(err) = room_entry.set_operator(&target, operator) {
+                return err.into();
+            }
+            self.broadcast_members(room_entry);
+            ResponseType::None
+        })
+    }
+
+    fn kick(&self, room: String, target: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::Moderate, |room_entry| {
+            if let Err(err) = room_entry.check_outranks(&user, &target) {
+                return err.into();
+            }
+            if !room_entry.leave(&target) {
+                let room = room.clone();
+                return ResponseError::UserNotInRoom { user: target, room }.into();
+            }
+            self.shared
+                .memberships
+                .lock()
+                .unwrap()
+                .leave(&target, &room);
+
+            self.tell_kicked(room_entry, &target, user.clone(), false);
+            self.broadcast_members(room_entry);
+            self.tell_left(room_entry, &target, LeaveReason::Kicked);
+            ResponseType::None
+        })
+    }
+
+    fn ban(&self, room: String, target: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::Moderate, |room_entry| {
+            if let Err(err) = room_entry.check_outranks(&user, &target) {
+                return err.into();
+            }
+
+            let was_member = room_entry.ban(target.clone());
+            let mut memberships = self.shared.memberships.lock().unwrap();
+            memberships.leave(&target, &room);
+            memberships.uninvite(&target, &room);
+            drop(memberships);
+            if was_member {
+                self.tell_kicked(room_entry, &target, user.clone(), true);
+            }
+            self.broadcast_members(room_entry);
+            if was_member {
+                self.tell_left(room_entry, &target, LeaveReason::Banned);
+            }
+            ResponseType::None
+        })
+    }
+
+    fn unban(&self, room: String, target: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::Unban, |room_entry| {
+            room_entry.unban(&target);
+            self.broadcast_members(room_entry);
+            ResponseType::None
+        })
+    }
+
+    fn set_private(
+        &self,
+        room: String,
+        private: bool,
+        key: Option<String>,
+        user: String,
+    ) -> ResponseType {
+        self.with_room(&room, &user, Permission::SetPrivate, |room_entry| {
+            room_entry.set_private(private, key);
+            if !private {
+                self.shared.memberships.lock().unwrap().clear_invites(&room);
+            }
+            self.broadcast_members(room_entry);
+            ResponseType::None
+        })
+    }
+
+    fn invite(&self, room: String, target: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::Invite, |_| {
+            let mut memberships = self.shared.memberships.lock().unwrap();
+            memberships.invite(&target, &room, &user);
+            drop(memberships);
+
+            // Offline users are told about the invite when they next say hello
+            let connections = self.shared.connections.read().unwrap();
+            for peer in connections.peers(&target) {
+                let response = Response::Invited {
+                    room: room.clone(),
+                    by: user.clone(),
+                };
+                peer.send(response);
+            }
+            ResponseType::None
+        })
+    }
+
+    fn set_topic(&self, room: String, topic: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::SetTopic, |room_entry| {
+            room_entry.set_topic(topic);
+            self.broadcast_members(room_entry);
+            ResponseType::None
+        })
+    }
+
+    /// Let a user know they were removed from a room, they won't get
+    /// the updated members list as they're no longer in it
+    fn tell_kicked(&self, room: &LockedRoom, target: &String, by: String, banned: bool) {
+        let response = Response::Kicked {
+            room: room.name().into(),
+            by,
+            banned,
+        };
+        self.fan_out(room, std::iter::once(target), response);
+    }
+
+    /// Let the rest of a room know someone is no longer in it
+    fn tell_left(&self, room: &LockedRoom, username: &str, reason: LeaveReason) {
+        let response = Response::UserLeft {
+            room: room.name().into(),
+            username: username.into(),
+            reason,
+        };
+        self.fan_out(room, room.members(), response);
+    }
+
+    fn list_rooms(&self, user: &str) -> ResponseType {
+        let rooms = self
+            .rooms()
+            .into_iter()
+            .filter_map(|room| {
+                let room = room.room.lock().unwrap();
+                (!room.is_private() || room.contains(user)).then(|| room.info())
+            })
+            .collect();
         ResponseType::Sender(Response::ListRooms { rooms })
     }
 
     fn list_users(&self) -> ResponseType {
-        let users = self.users.keys().cloned().collect();
+        let connections = self.shared.connections.read().unwrap();
+        let users = connections
+            .users
+            .keys()
+            .map(|user| connections.user_info(user))
+            .collect();
         ResponseType::Sender(Response::ListUsers { users })
     }
 
-    fn send(&mut self, target: Target, message: String, user: SocketAddr) -> ResponseType {
-        let user = self.user(user).to_owned();
+    fn set_status(&self, status: Status, note: Option<String>, user: String) -> ResponseType {
+        let mut connections = self.shared.connections.write().unwrap();
+        connections.statuses.insert(user.clone(), (status, note));
 
+        // Being online while every session is idle still shows as away
+        let UserInfo { status, note, .. } = connections.user_info(&user);
+        ResponseType::Broadcast(Response::StatusChanged {
+            username: user,
+            status,
+            note,
+        })
+    }
+
+    /// Note whether one of a user's sessions is idle, telling everyone if
+    /// that changes how they're shown
+    fn set_idle(&self, idle: bool, user: String, addr: SocketAddr) -> ResponseType {
+        let mut connections = self.shared.connections.write().unwrap();
+        let before = connections.user_info(&user);
+        let mut sessions = connections.sessions.get_mut(&user).into_iter().flatten();
+        if let Some(session) = sessions.find(|s| s.addr == Some(addr)) {
+            session.idle = idle;
+        }
+
+        match connections.status_change(before) {
+            Some(response) => ResponseType::Broadcast(response),
+            None => ResponseType::None,
+        }
+    }
+
+    fn send_message(
+        &self,
+        target: Target,
+        message: String,
+        user: String,
+        peer: &Peer,
+    ) -> ResponseType {
         match target {
-            Target::Room(room) => {
-                let response = Response::TellRoom {
-                    room: room.clone(),
-                    sender: user,
-                    message,
+            Target::Room(room) => self.send_room(room, message, user),
+            Target::Username(username) => self.send_direct(username, message, user, peer),
+        }
+    }
+
+    fn send_room(&self, room: String, message: String, user: String) -> ResponseType {
+        self.with_room(&room, &user, Permission::Send, |room_entry| {
+            // Recorded and queued while the room is locked so everyone in it
+            // gets messages in the same order
+            let target = Target::Room(room.clone());
+            let response = self.record(target, user.clone(), message).response();
+            self.fan_out(room_entry, room_entry.members(), response);
+            ResponseType::None
+        })
+    }
+
+    fn send_direct(
+        &self,
+        username: String,
+        message: String,
+        user: String,
+        peer: &Peer,
+    ) -> ResponseType {
+        // Connections stays locked so the message can't slip past a session
+        // that's logging in and replaying what it missed
+        let connections = self.shared.connections.read().unwrap();
+        // The sender wouldn't hear the message was held on to for later
+        let offline = !connections.is_online(&username);
+        if offline && !peer.supports(Capability::OfflineDelivery) {
+            return ResponseError::UserOffline(username).into();
+        }
+        let target = Target::Username(username.clone());
+        let first = !self
+            .shared
+            .history
+            .lock()
+            .unwrap()
+            .are_partners(&user, &username);
+        let entry = self.record(target, user.clone(), message);
+        let response = entry.response();
+
+        // Echo to every session the message was sent from so they stay in sync
+        connections.send_to(&user, response.clone());
+        if connections.is_online(&username) {
+            if username != user {
+                connections.send_to(&username, response);
+            }
+        } else {
+            // The echo has to arrive before its status
+            let queued = Response::Queued {
+                username: username.clone(),
+                ids: vec![entry.message.id],
+            };
+            connections.send_to(&user, queued);
+            self.shared
+                .mailbox
+                .lock()
+                .unwrap()
+                .push(username.clone(), entry);
+        }
+
+        // Presence is only sent between partners, so a new pair of them
+        // starts off knowing about each other
+        if first && username != user {
+            let online = connections.is_online(&username);
+            let presence = Response::Presence {
+                username: username.clone(),
+                online,
+            };
+            connections.send_to(&user, presence);
+            if online {
+                let presence = Response::Presence {
+                    username: user,
+                    online: true,
                 };
-                ResponseType::BroadcastRoom(room, response)
+                connections.send_to(&username, presence);
             }
+        }
+        ResponseType::None
+    }
+
+    /// Pass on that `user` is typing to whoever's connected, it's not worth
+    /// keeping for anyone who isn't
+    fn typing(&self, target: Target, user: String) -> ResponseType {
+        match target {
+            Target::Room(room) => self.with_room(&room, &user, Permission::Send, |room_entry| {
+                let connections = self.shared.connections.read().unwrap();
+                let response = Response::Typing {
+                    target: Target::Room(room.clone()),
+                    username: user.clone(),
+                };
+                let members = room_entry.members().filter(|m| **m != user);
+                let outboxes = members
+                    .flat_map(|m| connections.peers(m))
+                    .filter(|peer| understands(&peer.capabilities, &response))
+                    .map(|peer| peer.tx.clone())
+                    .collect();
+                room_entry.queue(outboxes, response);
+                ResponseType::None
+            }),
             Target::Username(username) => {
-                let response = Response::TellUser {
-                    username: username.clone(),
-                    sender: user,
-                    message,
+                // Like read markers, only for people already talking to each other
+                let history = self.shared.history.lock().unwrap();
+                if !history.are_partners(&user, &username) {
+                    return ResponseType::None;
+                }
+                drop(history);
+
+                let connections = self.shared.connections.read().unwrap();
+                let response = Response::Typing {
+                    target: Target::Username(user.clone()),
+                    username: user,
                 };
-                ResponseType::SenderAndUser(username, response)
+                for peer in connections.peers(&username) {
+                    peer.send(response.clone());
+                }
+                ResponseType::None
             }
         }
     }
 
-    fn keep_alive(&mut self, user: SocketAddr) -> ResponseType {
-        let user = self.user(user).to_owned();
-        let user = self.users.get_mut(&user).unwrap();
-        user.keep_alive = true;
+    /// Move a user's read marker forward, keeping all their sessions in step
+    fn mark_read(&self, target: Target, message_id: MessageId, user: String) -> ResponseType {
+        // Markers are only kept for conversations the user is part of
+        let allowed = match &target {
+            Target::Room(room) => self
+                .room(room)
+                .is_some_and(|room| room.room.lock().unwrap().contains(&user)),
+            Target::Username(username) => {
+                let history = self.shared.history.lock().unwrap();
+                history.are_partners(&user, username)
+            }
+        };
+        if !allowed {
+            return ResponseType::None;
+        }
+
+        let mut read_markers = self.shared.read_markers.lock().unwrap();
+        if read_markers.mark(&user, target.clone(), message_id) {
+            drop(read_markers);
+            self.send(&user, Response::MarkedRead { target, message_id });
+        }
         ResponseType::None
     }
 
-    fn user(&self, addr: SocketAddr) -> &str {
-        self.addr_to_user.get(&addr).unwrap()
+    fn record(&self, target: Target, sender: String, message: String) -> Entry {
+        let mut history = self.shared.history.lock().unwrap();
+        history.record(target, sender, message).clone()
     }
 
-    fn kick_keep_alive(&mut self) {
-        for peer in self.users.values_mut() {
-            if !peer.keep_alive {
-                peer.kill_tx.send(Kill).unwrap();
-            } else {
-                peer.keep_alive = false;
+    fn fetch_history(
+        &self,
+        target: Target,
+        before: Option<MessageId>,
+        limit: usize,
+        user: &str,
+    ) -> ResponseType {
+        let conversation = match &target {
+            Target::Room(room) => {
+                let Some(room_entry) = self.room(room) else {
+                    return ResponseError::RoomDoesNotExist(room.clone()).into();
+                };
+                let room_entry = room_entry.room.lock().unwrap();
+                if let Err(err) = room_entry.check(user, Permission::ReadHistory) {
+                    return err.into();
+                }
+                Conversation::Room(room.clone())
             }
+            Target::Username(username) => Conversation::direct(user, username),
+        };
+
+        let messages = self
+            .shared
+            .history
+            .lock()
+            .unwrap()
+            .before(
+                &conversation,
+                before.unwrap_or(MessageId::MAX),
+                limit.min(MAX_FETCH_LIMIT),
+            )
+            .iter()
+            .map(|entry| entry.message.clone())
+            .collect();
+
+        ResponseType::Sender(Response::History { target, messages })
+    }
+
+    fn room(&self, room: &str) -> Option<Arc<RoomEntry>> {
+        self.shared.rooms.read().unwrap().get(room).cloned()
+    }
+
+    /// Every room, so they can be locked one at a time without holding the map
+    fn rooms(&self) -> Vec<Arc<RoomEntry>> {
+        self.shared
+            .rooms
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Lock a room and run `f` on it, if it exists and `user` has `permission`
+    fn with_room(
+        &self,
+        room: &str,
+        user: &str,
+        permission: Permission,
+        f: impl FnOnce(&mut LockedRoom) -> ResponseType,
+    ) -> ResponseType {
+        let Some(room_entry) = self.room(room) else {
+            return ResponseError::RoomDoesNotExist(room.into()).into();
+        };
+        let mut room_entry = room_entry.lock();
+        match room_entry.check(user, permission) {
+            Ok(()) => f(&mut room_entry),
+            Err(err) => err.into(),
         }
     }
 
-    fn users_rooms_mut<'a>(
-        &'a mut self,
-        user: &'a str,
-    ) -> impl Iterator<Item = (&String, &mut HashSet<String>)> {
-        self.rooms
-            .iter_mut()
-            .filter(|(_, users)| users.contains(user))
+    /// Tell everyone in a room who's in it, queued while it's still locked
+    /// so lists arrive in the order the changes were made
+    fn broadcast_members(&self, room: &LockedRoom) {
+        self.fan_out(room, room.members(), self.list_members(room));
     }
 
-    fn remove_peer(&mut self, peer: &Peer) {
-        let user = self.user(peer.addr).to_owned();
-        self.addr_to_user.remove(&peer.addr);
-        self.users.remove(&user);
+    fn list_members(&self, room: &Room) -> Response {
+        let connections = self.shared.connections.read().unwrap();
+        room.list_members(|user| connections.status(user))
+    }
 
-        // Remove user from each room they're in and get a list of updated users
-        // to send to all users in the room
-        let mut rooms_to_notify = Vec::new();
-        for (room, users) in self.users_rooms_mut(&user) {
-            users.remove(&user);
-            rooms_to_notify.push((
-                room.clone(),
-                Response::ListMembers {
-                    room: room.clone(),
-                    users: users.iter().cloned().collect(),
-                },
-            ));
+    /// Queue for every session of each of `users`, sent in order with
+    /// everything else for the room once it's unlocked
+    fn fan_out<'a>(
+        &self,
+        room: &LockedRoom,
+        users: impl Iterator<Item = &'a String>,
+        response: Response,
+    ) {
+        let connections = self.shared.connections.read().unwrap();
+        let outboxes = users
+            .flat_map(|user| connections.outboxes(user, &response))
+            .collect();
+        drop(connections);
+        room.queue(outboxes, response);
+    }
+
+    pub fn send(&self, user: &str, response: Response) {
+        let connections = self.shared.connections.read().unwrap();
+        connections.send_to(user, response)
+    }
+
+    pub fn broadcast(&self, response: Response) {
+        let connections = self.shared.connections.read().unwrap();
+        for peer in connections.users.values().flat_map(HashMap::values) {
+            peer.send(response.clone());
         }
+    }
 
-        for (room, response) in rooms_to_notify {
-            self.broadcast_room(&room, response)
+    /// End sessions that weren't resumed in time, users leave their rooms
+    /// once their last session ends
+    pub fn expire_sessions(&self) {
+        let grace = self.shared.settings.resume_grace;
+        let expired = self
+            .shared
+            .connections
+            .write()
+            .unwrap()
+            .expire_sessions(grace);
+
+        for (user, reason) in expired {
+            let rooms: Vec<_> = {
+                let memberships = self.shared.memberships.lock().unwrap();
+                memberships.rooms(&user).cloned().collect()
+            };
+            for room in rooms {
+                let Some(room) = self.room(&room) else {
+                    continue;
+                };
+                let mut room = room.lock();
+                // They may have logged in again since
+                let connections = self.shared.connections.read().unwrap();
+                if connections.sessions.contains_key(&user) || !room.contains(&user) {
+                    continue;
+                }
+                drop(connections);
+
+                room.leave(&user);
+                let name = room.name().to_owned();
+                self.shared.memberships.lock().unwrap().leave(&user, &name);
+                self.broadcast_members(&room);
+                self.tell_left(&room, &user, reason);
+            }
         }
     }
 
-    fn broadcast_room(&self, room: &str, response: Response) {
-        for user in &self.rooms[room] {
-            let user = &self.users[user];
-            user.tx.send(response.clone()).unwrap()
+    /// Queue for a detached session to hold on to what it misses, as deep
+    /// as a connection's and just as strict with it
+    fn missed_queue(&self) -> (Outbox, outbox::Receiver) {
+        let settings = &self.shared.settings;
+        let metrics = self.shared.metrics.clone();
+        outbox::channel(settings.outbound_queue, settings.slow_consumer, metrics)
+    }
+
+    /// Detach a closed connection's session, letting the user's direct
+    /// message partners know if they went offline
+    pub fn remove_peer(&self, peer: &Peer, reason: LeaveReason) {
+        let mut connections = self.shared.connections.write().unwrap();
+        let before = connections
+            .addr_to_user
+            .get(&peer.addr)
+            .map(|user| connections.user_info(user));
+        let Some(user) = connections.remove_peer(peer, reason, self.missed_queue()) else {
+            // Still online elsewhere, though maybe only idle sessions are left
+            let status_change = before.and_then(|before| connections.status_change(before));
+            drop(connections);
+            if let Some(response) = status_change {
+                self.broadcast(response);
+            }
+            return;
+        };
+
+        let history = self.shared.history.lock().unwrap();
+        for partner in history.partners(&user).filter(|p| **p != user) {
+            let response = Response::Presence {
+                username: user.clone(),
+                online: false,
+            };
+            for peer in connections.peers(partner) {
+                peer.send(response.clone());
+            }
         }
     }
 }
 
-impl ServerState {
-    pub fn apply(&self, command: Command, peer: Peer) -> ResponseType {
-        let mut state = self.shared.state.lock().unwrap();
-        match command {
-            Command::Hello { username } => state.hello(username, peer),
-            Command::JoinOrCreate { room } => state.join_or_create(room, peer.addr),
-            Command::Leave { room } => state.leave_room(room, peer.addr),
-            Command::KeepAlive => state.keep_alive(peer.addr),
-            Command::ListRooms => state.list_rooms(),
-            Command::ListUsers => state.list_users(),
-            Command::Send { target, message } => state.send(target, message, peer.addr),
+/// Random secret a session can be resumed with
+fn resume_token() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Check the client speaks a protocol version this server supports and agree on
+/// the version and capabilities both sides will use
+fn negotiate(handshake: &Handshake) -> Result<(u32, Vec<Capability>), ResponseError> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&handshake.version) {
+        return Err(ResponseError::IncompatibleVersion {
+            version: handshake.version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+    }
+
+    let capabilities = CAPABILITIES
+        .iter()
+        .filter(|c| handshake.capabilities.contains(c))
+        .copied()
+        .collect();
+
+    Ok((handshake.version, capabilities))
+}
+
+/// Whether a connection that agreed on `capabilities` can be sent `response`
+fn understands(capabilities: &[Capability], response: &Response) -> bool {
+    response
+        .capability()
+        .is_none_or(|capability| capabilities.contains(&capability))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use common::commands::{Role, CAPABILITIES};
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::{
+        config::SlowConsumer,
+        outbox::{self, Metrics},
+    };
+
+    /// A logged in connection and whatever's been sent to it
+    struct TestPeer {
+        peer: Peer,
+        rx: outbox::Receiver,
+        _kill_rx: mpsc::UnboundedReceiver<Kill>,
+    }
+
+    impl TestPeer {
+        fn new() -> Self {
+            static PORT: AtomicU16 = AtomicU16::new(1);
+            let addr = SocketAddr::from(([127, 0, 0, 1], PORT.fetch_add(1, Ordering::Relaxed)));
+            let metrics = Arc::new(Metrics::default());
+            let (tx, rx) = outbox::channel(64, SlowConsumer::Disconnect, metrics);
+            let (kill_tx, kill_rx) = mpsc::unbounded_channel();
+            Self {
+                peer: Peer::new(addr, tx, kill_tx),
+                rx,
+                _kill_rx: kill_rx,
+            }
+        }
+
+        /// Log in as `username`, skipping the password check, and throw away
+        /// what's sent on the way
+        fn login(state: &ServerState, username: &str) -> Self {
+            let mut test = Self::login_keeping(state, username);
+            test.responses();
+            test
+        }
+
+        /// Log in as `username` keeping what's sent on the way
+        fn login_keeping(state: &ServerState, username: &str) -> Self {
+            let test = Self::new();
+            let mut accounts = state.shared.accounts.lock().unwrap();
+            let _ = accounts.register(username.into(), String::new());
+            drop(accounts);
+
+            let capabilities = CAPABILITIES.to_vec();
+            let peer = test.peer.clone();
+            state.attach(username.into(), None, peer, PROTOCOL_VERSION, capabilities);
+            test
+        }
+
+        async fn apply(&self, state: &ServerState, command: Command) -> ResponseType {
+            state.apply(command, self.peer.clone()).await
+        }
+
+        /// The error `command` was answered with
+        async fn error(&self, state: &ServerState, command: Command) -> ResponseError {
+            match self.apply(state, command).await {
+                ResponseType::Sender(Response::Err(err)) => err,
+                _ => panic!("expected an error"),
+            }
+        }
+
+        /// Everything sent so far
+        fn responses(&mut self) -> Vec<Response> {
+            let mut responses = Vec::new();
+            while let Some(Ok(response)) = self.rx.recv().now_or_never() {
+                responses.push(response);
+            }
+            responses
         }
     }
 
-    pub fn send(&self, user: &str, response: Response) {
-        let state = self.shared.state.lock().unwrap();
-        if let Some(peer) = state.users.get(user) {
-            peer.tx.send(response).unwrap();
+    fn join(room: &str) -> Command {
+        Command::JoinOrCreate {
+            room: room.into(),
+            key: None,
         }
     }
 
-    pub fn broadcast(&self, response: Response) {
-        for user in self.shared.state.lock().unwrap().users.values() {
-            user.tx.send(response.clone()).unwrap();
+    fn send(target: Target) -> Command {
+        Command::Send {
+            target,
+            message: "hi".into(),
+        }
+    }
+
+    fn room(room: &str) -> Target {
+        Target::Room(room.into())
+    }
+
+    fn resume(username: &str, token: String) -> Command {
+        Command::Resume {
+            username: username.into(),
+            token,
+            handshake: Handshake::default(),
+        }
+    }
+
+    /// Token `username`'s first session can be resumed with
+    fn token(state: &ServerState, username: &str) -> String {
+        let connections = state.shared.connections.read().unwrap();
+        connections.sessions[username][0].token.clone()
+    }
+
+    #[tokio::test]
+    async fn commands_need_login() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let peer = TestPeer::new();
+
+        let err = peer.error(&state, join("room")).await;
+        assert!(matches!(err, ResponseError::NotAuthenticated));
+    }
+
+    #[tokio::test]
+    async fn send_to_unknown_room() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+
+        let err = alice.error(&state, send(room("nowhere"))).await;
+        assert!(matches!(err, ResponseError::RoomDoesNotExist(room) if room == "nowhere"));
+        let err = alice
+            .error(
+                &state,
+                Command::Leave {
+                    room: "nowhere".into(),
+                },
+            )
+            .await;
+        assert!(matches!(err, ResponseError::RoomDoesNotExist(_)));
+    }
+
+    #[tokio::test]
+    async fn only_members_send_and_read() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+
+        alice.apply(&state, join("room")).await;
+        alice.responses();
+
+        let err = bob.error(&state, send(room("room"))).await;
+        assert!(matches!(err, ResponseError::UserNotInRoom { user, .. } if user == "bob"));
+        let fetch = Command::FetchHistory {
+            target: room("room"),
+            before: None,
+            limit: 10,
+        };
+        let err = bob.error(&state, fetch).await;
+        assert!(matches!(err, ResponseError::UserNotInRoom { .. }));
+        let err = bob
+            .error(
+                &state,
+                Command::Leave {
+                    room: "room".into(),
+                },
+            )
+            .await;
+        assert!(matches!(err, ResponseError::UserNotInRoom { .. }));
+
+        // Nothing from bob reached the room
+        assert!(alice.responses().is_empty());
+    }
+
+    #[tokio::test]
+    async fn members_get_room_messages() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut alice = TestPeer::login(&state, "alice");
+        let mut bob = TestPeer::login(&state, "bob");
+
+        alice.apply(&state, join("room")).await;
+        bob.apply(&state, join("room")).await;
+        alice.responses();
+        bob.responses();
+
+        bob.apply(&state, send(room("room"))).await;
+        for peer in [&mut alice, &mut bob] {
+            let responses = peer.responses();
+            assert!(
+                matches!(&responses[..], [Response::TellRoom { message, .. }] if message.sender == "bob")
+            );
         }
     }
 
-    pub fn broadcast_room(&self, room: &str, response: Response) {
-        let state = self.shared.state.lock().unwrap();
-        state.broadcast_room(room, response)
+    #[tokio::test]
+    async fn only_operators_moderate() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+
+        alice.apply(&state, join("room")).await;
+        bob.apply(&state, join("room")).await;
+
+        let kick = |user: &str| Command::Kick {
+            room: "room".into(),
+            user: user.into(),
+        };
+        let err = bob.error(&state, kick("alice")).await;
+        assert!(matches!(err, ResponseError::NotRoomOperator(_)));
+
+        alice.apply(&state, kick("bob")).await;
+        let room = state.room("room").unwrap();
+        let room = room.lock();
+        assert!(!room.contains("bob"));
+        assert_eq!(room.role("alice"), Role::Owner);
+    }
+
+    #[tokio::test]
+    async fn direct_messages_wait_for_offline_users() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+        state.remove_peer(&bob.peer, LeaveReason::Disconnected);
+        alice.responses();
+
+        alice
+            .apply(&state, send(Target::Username("bob".into())))
+            .await;
+        let responses = alice.responses();
+        assert!(matches!(
+            &responses[..],
+            [Response::TellUser { .. }, Response::Queued { .. }, ..]
+        ));
+
+        let mut bob = TestPeer::login_keeping(&state, "bob");
+        let responses = bob.responses();
+        assert!(responses
+            .iter()
+            .any(|r| matches!(r, Response::TellUser { .. })));
+        let responses = alice.responses();
+        assert!(responses
+            .iter()
+            .any(|r| matches!(r, Response::Delivered { .. })));
     }
 
-    pub fn kick_keep_alive(&self) {
-        let mut state = self.shared.state.lock().unwrap();
-        state.kick_keep_alive();
+    #[tokio::test]
+    async fn sessions_stay_in_sync() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let laptop = TestPeer::login(&state, "alice");
+        let mut phone = TestPeer::login(&state, "alice");
+        let mut bob = TestPeer::login(&state, "bob");
+
+        laptop
+            .apply(&state, send(Target::Username("bob".into())))
+            .await;
+        let echoed = phone.responses();
+        assert!(echoed
+            .iter()
+            .any(|r| matches!(r, Response::TellUser { username, .. } if username == "bob")));
+        assert!(bob
+            .responses()
+            .iter()
+            .any(|r| matches!(r, Response::TellUser { .. })));
+
+        let mark = Command::MarkRead {
+            target: Target::Username("bob".into()),
+            message_id: 0,
+        };
+        laptop.apply(&state, mark).await;
+        let marked = phone.responses();
+        assert!(matches!(
+            &marked[..],
+            [Response::MarkedRead { message_id: 0, .. }]
+        ));
     }
 
-    pub fn remove_peer(&self, peer: &Peer) {
-        let mut state = self.shared.state.lock().unwrap();
-        state.remove_peer(peer)
+    #[tokio::test]
+    async fn leaving_reaches_other_sessions() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let laptop = TestPeer::login(&state, "alice");
+        let mut phone = TestPeer::login(&state, "alice");
+        laptop.apply(&state, join("room")).await;
+        phone.responses();
+
+        laptop
+            .apply(
+                &state,
+                Command::Leave {
+                    room: "room".into(),
+                },
+            )
+            .await;
+        let responses = phone.responses();
+        assert!(matches!(
+            &responses[..],
+            [Response::UserLeft { username, reason: LeaveReason::Left, .. }] if username == "alice"
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_markers_only_for_own_conversations() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let mut alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+        bob.apply(&state, join("room")).await;
+
+        for target in [
+            room("room"),
+            room("nowhere"),
+            Target::Username("bob".into()),
+        ] {
+            let mark = Command::MarkRead {
+                target,
+                message_id: 5,
+            };
+            alice.apply(&state, mark).await;
+        }
+        assert!(alice.responses().is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_sessions_get_room_history() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        alice.apply(&state, join("room")).await;
+        alice.apply(&state, send(room("room"))).await;
+
+        let mut other = TestPeer::login_keeping(&state, "alice");
+        let responses = other.responses();
+        let members = responses
+            .iter()
+            .position(|r| matches!(r, Response::ListMembers { .. }))
+            .unwrap();
+        let message = responses
+            .iter()
+            .position(|r| matches!(r, Response::TellRoom { room, .. } if room == "room"))
+            .unwrap();
+        assert!(members < message);
+    }
+
+    #[tokio::test]
+    async fn typing_only_to_partners() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let mut bob = TestPeer::login(&state, "bob");
+        let typing = || Command::Typing {
+            target: Target::Username("bob".into()),
+        };
+
+        alice.apply(&state, typing()).await;
+        assert!(bob.responses().is_empty());
+
+        alice
+            .apply(&state, send(Target::Username("bob".into())))
+            .await;
+        bob.responses();
+        alice.apply(&state, typing()).await;
+        let responses = bob.responses();
+        assert!(
+            matches!(&responses[..], [Response::Typing { username, .. }] if username == "alice")
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_gets_missed_messages() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+        alice.apply(&state, join("room")).await;
+        bob.apply(&state, join("room")).await;
+
+        let token = token(&state, "alice");
+        state.remove_peer(&alice.peer, LeaveReason::Disconnected);
+        bob.apply(&state, send(room("room"))).await;
+
+        let mut resumed = TestPeer::new();
+        resumed.apply(&state, resume("alice", token)).await;
+        let responses = resumed.responses();
+        assert!(matches!(&responses[0], Response::Welcome(welcome) if welcome.rooms == ["room"]));
+        assert!(responses
+            .iter()
+            .any(|r| matches!(r, Response::TellRoom { .. })));
+    }
+
+    #[tokio::test]
+    async fn missing_too_much_ends_session() {
+        let config = Config {
+            outbound_queue: 2,
+            ..Config::default()
+        };
+        let state = ServerState::new(&config).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let bob = TestPeer::login(&state, "bob");
+        alice.apply(&state, join("room")).await;
+        bob.apply(&state, join("room")).await;
+
+        let token = token(&state, "alice");
+        state.remove_peer(&alice.peer, LeaveReason::Disconnected);
+        for _ in 0..3 {
+            bob.apply(&state, send(room("room"))).await;
+        }
+
+        let resumed = TestPeer::new();
+        let err = resumed.error(&state, resume("alice", token)).await;
+        assert!(matches!(err, ResponseError::InvalidResumeToken));
+
+        state.expire_sessions();
+        let room = state.room("room").unwrap();
+        assert!(!room.lock().contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn grace_period_ends_sessions() {
+        let config = Config {
+            resume_grace: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let state = ServerState::new(&config).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let mut bob = TestPeer::login(&state, "bob");
+        alice.apply(&state, join("room")).await;
+        bob.apply(&state, join("room")).await;
+
+        let token = token(&state, "alice");
+        state.remove_peer(&alice.peer, LeaveReason::TimedOut);
+        bob.responses();
+
+        // Still within the grace period nothing happens
+        state.expire_sessions();
+        assert!(state.room("room").unwrap().lock().contains("alice"));
+        assert!(bob.responses().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state.expire_sessions();
+        assert!(!state.room("room").unwrap().lock().contains("alice"));
+        let left = bob.responses().into_iter().any(|response| {
+            matches!(
+                response,
+                Response::UserLeft { username, reason: LeaveReason::TimedOut, .. }
+                    if username == "alice"
+            )
+        });
+        assert!(left);
+
+        let resumed = TestPeer::new();
+        let err = resumed.error(&state, resume("alice", token)).await;
+        assert!(matches!(err, ResponseError::InvalidResumeToken));
+    }
+
+    #[tokio::test]
+    async fn resume_needs_right_token() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        let token = token(&state, "alice");
+        state.remove_peer(&alice.peer, LeaveReason::Disconnected);
+
+        let resumed = TestPeer::new();
+        let err = resumed.error(&state, resume("alice", "wrong".into())).await;
+        assert!(matches!(err, ResponseError::InvalidResumeToken));
+        let err = resumed.error(&state, resume("bob", token.clone())).await;
+        assert!(matches!(err, ResponseError::InvalidResumeToken));
+
+        // Guessing wrong doesn't lose the session
+        let mut resumed = TestPeer::new();
+        resumed.apply(&state, resume("alice", token)).await;
+        assert!(matches!(resumed.responses()[0], Response::Welcome(_)));
+    }
+
+    #[tokio::test]
+    async fn away_once_idle_everywhere() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let first = TestPeer::login(&state, "alice");
+        let second = TestPeer::login(&state, "alice");
+
+        async fn status(state: &ServerState, peer: &TestPeer, command: Command) -> Option<Status> {
+            match peer.apply(state, command).await {
+                ResponseType::Broadcast(Response::StatusChanged { status, .. }) => Some(status),
+                _ => None,
+            }
+        }
+        let idle = |idle| Command::Idle { idle };
+        let set = |status| Command::SetStatus { status, note: None };
+
+        assert_eq!(status(&state, &first, idle(true)).await, None);
+        assert_eq!(
+            status(&state, &second, idle(true)).await,
+            Some(Status::Away)
+        );
+        assert_eq!(
+            status(&state, &second, idle(false)).await,
+            Some(Status::Online)
+        );
+
+        // Coming back doesn't undo a status they chose
+        assert_eq!(
+            status(&state, &second, set(Status::Busy)).await,
+            Some(Status::Busy)
+        );
+        assert_eq!(status(&state, &second, idle(true)).await, None);
+        assert_eq!(status(&state, &second, idle(false)).await, None);
+
+        // Going idle isn't just up to the session that last said so
+        assert_eq!(
+            status(&state, &second, set(Status::Online)).await,
+            Some(Status::Online)
+        );
+        assert_eq!(
+            status(&state, &second, idle(true)).await,
+            Some(Status::Away)
+        );
+        let third = TestPeer::login(&state, "alice");
+        let connections = state.shared.connections.read().unwrap();
+        assert_eq!(connections.status("alice"), Status::Online);
+        drop(connections);
+
+        state.remove_peer(&third.peer, LeaveReason::Left);
+        let connections = state.shared.connections.read().unwrap();
+        assert_eq!(connections.status("alice"), Status::Away);
+    }
+
+    #[tokio::test]
+    async fn logging_in_finds_rooms_and_invites() {
+        let state = ServerState::new(&Config::default()).unwrap();
+        let alice = TestPeer::login(&state, "alice");
+        alice.apply(&state, join("room")).await;
+        alice.apply(&state, join("secret")).await;
+        let private = Command::SetPrivate {
+            room: "secret".into(),
+            private: true,
+            key: None,
+        };
+        alice.apply(&state, private).await;
+        let invite = Command::Invite {
+            room: "secret".into(),
+            user: "bob".into(),
+        };
+        alice.apply(&state, invite).await;
+
+        let mut bob = TestPeer::login_keeping(&state, "bob");
+        let invited = bob.responses().into_iter().any(|response| {
+            matches!(response, Response::Invited { room, by } if room == "secret" && by == "alice")
+        });
+        assert!(invited);
+
+        let mut other = TestPeer::login_keeping(&state, "alice");
+        let responses = other.responses();
+        assert!(
+            matches!(&responses[0], Response::Welcome(welcome) if welcome.rooms == ["room", "secret"])
+        );
+        let members = responses
+            .iter()
+            .filter(|response| matches!(response, Response::ListMembers { .. }))
+            .count();
+        assert_eq!(members, 2);
     }
 }
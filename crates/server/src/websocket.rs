@@ -0,0 +1,93 @@
+use std::io;
+
+use common::{
+    connection::Stream,
+    frame::{Frame, MAX_FRAME_SIZE},
+    Error, Result,
+};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::{
+    tungstenite::{self, protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+
+use crate::server::Transport;
+
+/// Connection for browsers and bots that can't speak the length prefixed
+/// frame protocol, each websocket text message carries one command or response
+pub struct WsConnection<S> {
+    stream: WebSocketStream<S>,
+}
+
+impl<S: Stream> WsConnection<S> {
+    /// Complete the websocket handshake over an accepted stream
+    pub async fn accept(stream: S) -> Result<Self> {
+        let config = WebSocketConfig::default().max_message_size(Some(MAX_FRAME_SIZE));
+        let stream = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+            .await
+            .map_err(ws_error)?;
+
+        Ok(Self { stream })
+    }
+}
+
+impl<S: Stream> Transport for WsConnection<S> {
+    async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let message = match self.stream.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(tungstenite::Error::ConnectionClosed)) | None => return Ok(None),
+                Some(Err(err)) => return Err(ws_error(err)),
+            };
+
+            match message {
+                Message::Text(text) => return Ok(Some(Frame::from(text.as_str()))),
+                Message::Binary(bytes) => {
+                    return Ok(Some(Frame::from(String::from_utf8_lossy(&bytes))))
+                }
+                Message::Close(_) => return Ok(None),
+                // Pings are answered by tungstenite itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let message = Message::text(frame.raw());
+        self.stream.send(message).await.map_err(ws_error)
+    }
+}
+
+fn ws_error(err: tungstenite::Error) -> Error {
+    match err {
+        tungstenite::Error::Io(err) => Error::Io(err),
+        err => Error::Io(io::Error::other(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_per_message() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async("ws://localhost/", client),
+            WsConnection::accept(server)
+        );
+        let (mut client, _) = client.unwrap();
+        let mut server = server.unwrap();
+
+        client.send(Message::text("\"ListRooms\"")).await.unwrap();
+        let frame = server.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame.raw(), "\"ListRooms\"");
+
+        server.write_frame(&Frame::from("\"KeepAlive\"")).await.unwrap();
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(message, Message::text("\"KeepAlive\""));
+
+        client.close(None).await.unwrap();
+        assert_eq!(server.read_frame().await.unwrap(), None);
+    }
+}